@@ -0,0 +1,154 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Where a write to a path really ends up, so saving through a symlink replaces what it points to
+/// instead of the link itself
+fn resolve(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    // Give up on links that go around in circles rather than chasing them forever
+    for _ in 0..32 {
+        match fs::read_link(&path) {
+            Ok(target) => {
+                path = match path.parent() {
+                    Some(dir) => dir.join(target),
+                    None => target
+                };
+            },
+            Err(_) => break
+        }
+    }
+    path
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.mfte-{}.tmp", name, process::id()))
+}
+
+#[cfg(unix)]
+fn hard_linked(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn hard_linked(_: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn copy_owner(meta: &fs::Metadata, path: &Path) {
+    use std::os::unix::fs::{chown, MetadataExt};
+    // Only root can give a file away, so this failing is normal and fine
+    let _ = chown(path, Some(meta.uid()), Some(meta.gid()));
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_: &fs::Metadata, _: &Path) {}
+
+/// Makes a rename stick even if the power goes out right after
+#[cfg(unix)]
+fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(d) = fs::File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) {}
+
+fn write_in_place(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).truncate(true).open(path)?;
+    f.write_all(bytes)?;
+    f.sync_all()
+}
+
+fn write_new(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    f.write_all(bytes)?;
+    f.sync_all()
+}
+
+/// Replaces the contents of a file so that it's never left half-written. Everything goes into a
+/// temp file next to it first, which then gets renamed over the original. Hard-linked files are
+/// rewritten in place once the temp file is safely on disk, since renaming would break the link.
+pub fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let path = resolve(path);
+    let temp = temp_path(&path);
+    let meta = fs::metadata(&path).ok();
+
+    if let Err(e) = write_new(&temp, bytes) {
+        let _ = fs::remove_file(&temp);
+        // A file we can write to in a directory we can't is still worth saving, just less carefully
+        if e.kind() == io::ErrorKind::PermissionDenied && meta.is_some() {
+            return write_in_place(&path, bytes);
+        }
+        return Err(e);
+    }
+
+    if let Some(ref meta) = meta {
+        if hard_linked(meta) {
+            return match write_in_place(&path, bytes) {
+                Ok(()) => fs::remove_file(&temp),
+                // Keep the temp file around, since now it's the only complete copy
+                Err(e) => Err(io::Error::new(e.kind(),
+                    format!("{} (the new contents are in {})", e, temp.display())))
+            };
+        }
+    }
+
+    let result = (|| {
+        if let Some(ref meta) = meta {
+            copy_owner(meta, &temp);
+            fs::set_permissions(&temp, meta.permissions())?;
+        }
+        fs::rename(&temp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    } else {
+        sync_dir(&path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_links_and_permissions() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = env::temp_dir().join(format!("mfte_atomic_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let real = dir.join("real.txt");
+        let link = dir.join("link.txt");
+        let hard = dir.join("hard.txt");
+        fs::write(&real, "old").unwrap();
+        fs::set_permissions(&real, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("real.txt", &link).unwrap();
+        fs::hard_link(&real, &hard).unwrap();
+
+        write(&link, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&real).unwrap(), b"new");
+        assert_eq!(fs::read(&hard).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        fs::remove_file(&hard).unwrap();
+        write(&link, b"newer").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&real).unwrap(), b"newer");
+        assert_eq!(fs::metadata(&real).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,27 +13,69 @@ pub enum IndentSize {
     Tab
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum EndOfLine {
     Lf,
     CrLf,
     Cr
 }
 
+impl EndOfLine {
+    pub fn sep(&self) -> &'static str {
+        match *self {
+            EndOfLine::Lf => "\n",
+            EndOfLine::CrLf => "\r\n",
+            EndOfLine::Cr => "\r"
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EndOfLine::Lf => "LF",
+            EndOfLine::CrLf => "CRLF",
+            EndOfLine::Cr => "CR"
+        }
+    }
+
+    /// The one after this, for flipping through them with a single key
+    pub fn next(&self) -> EndOfLine {
+        match *self {
+            EndOfLine::Lf => EndOfLine::CrLf,
+            EndOfLine::CrLf => EndOfLine::Cr,
+            EndOfLine::Cr => EndOfLine::Lf
+        }
+    }
+}
+
 pub enum Charset {
     Latin1,
     UTF8,
+    UTF8BOM,
     UTF16BE,
     UTF16LE
 }
 
+impl Charset {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Charset::Latin1 => "Latin-1",
+            Charset::UTF8 => "UTF-8",
+            Charset::UTF8BOM => "UTF-8 BOM",
+            Charset::UTF16BE => "UTF-16BE",
+            Charset::UTF16LE => "UTF-16LE",
+        }
+    }
+}
+
 pub struct Config {
     indent_style: IndentStyle,
     indent_size: IndentSize,
     tab_width: u8,
-    end_of_line: EndOfLine,
+    end_of_line: Option<EndOfLine>,
     charset: Charset,
-    pub trim_trailing_whitespace: bool,
-    pub insert_final_newline: bool,
+    /// These are only set when EditorConfig says so, since otherwise a file should be left how it was
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
 }
 
 impl Config {
@@ -42,10 +84,10 @@ impl Config {
             indent_style: IndentStyle::Space,
             indent_size: IndentSize::Size(4),
             tab_width: 4,
-            end_of_line: EndOfLine::Lf,
+            end_of_line: None,
             charset: Charset::UTF8,
-            trim_trailing_whitespace: true,
-            insert_final_newline: true,
+            trim_trailing_whitespace: None,
+            insert_final_newline: None,
         };
         if let Some(path) = path {
             let path = Path::new(path);
@@ -73,15 +115,18 @@ impl Config {
                     if let Ok(width) = width.parse() {
                         result.tab_width = width;
                     }
+                } else if let IndentSize::Size(n) = result.indent_size {
+                    // EditorConfig says tab_width defaults to indent_size when that's a number
+                    result.tab_width = n;
                 }
 
                 if let Some(eol) = conf.get("end_of_line") {
                     if eol == "cr" {
-                        result.end_of_line = EndOfLine::Cr;
+                        result.end_of_line = Some(EndOfLine::Cr);
                     } else if eol == "crlf" {
-                        result.end_of_line = EndOfLine::CrLf;
+                        result.end_of_line = Some(EndOfLine::CrLf);
                     } else if eol == "lf" {
-                        result.end_of_line = EndOfLine::Lf;
+                        result.end_of_line = Some(EndOfLine::Lf);
                     }
                 }
 
@@ -90,6 +135,8 @@ impl Config {
                         result.charset = Charset::Latin1;
                     } else if charset == "utf-8" {
                         result.charset = Charset::UTF8;
+                    } else if charset == "utf-8-bom" {
+                        result.charset = Charset::UTF8BOM;
                     } else if charset == "utf-16be" {
                         result.charset = Charset::UTF16BE;
                     } else if charset == "utf-16le" {
@@ -99,17 +146,17 @@ impl Config {
 
                 if let Some(ttw) = conf.get("trim_trailing_whitespace") {
                     if ttw == "true" {
-                        result.trim_trailing_whitespace = true;
+                        result.trim_trailing_whitespace = Some(true);
                     } else if ttw == "false" {
-                        result.trim_trailing_whitespace = false;
+                        result.trim_trailing_whitespace = Some(false);
                     }
                 }
 
                 if let Some(ifn) = conf.get("insert_final_newline") {
                     if ifn == "true" {
-                        result.insert_final_newline = true;
+                        result.insert_final_newline = Some(true);
                     } else if ifn == "false" {
-                        result.insert_final_newline = false;
+                        result.insert_final_newline = Some(false);
                     }
                 }
             }
@@ -130,11 +177,17 @@ impl Config {
         }
     }
     
-    pub fn line_sep(&self) -> &str {
-        match self.end_of_line {
-            EndOfLine::Lf => "\n",
-            EndOfLine::CrLf => "\r\n",
-            EndOfLine::Cr => "\r"
-        }
+    pub fn charset(&self) -> &Charset {
+        &self.charset
+    }
+
+    /// How many columns a tab takes up on screen
+    pub fn tab_width(&self) -> usize {
+        self.tab_width as usize
+    }
+
+    /// The line ending EditorConfig asks for, if it asks for one at all
+    pub fn end_of_line(&self) -> Option<EndOfLine> {
+        self.end_of_line
     }
 }
@@ -0,0 +1,210 @@
+use super::config::Charset;
+
+use std::str;
+
+const BOM: char = '\u{FEFF}';
+
+/// Bytes that aren't valid UTF-8 or UTF-16 are kept as characters from the end of the last private use plane,
+/// one per byte, so they can be shown as escapes and written back out exactly as they were.
+const RAW_BYTES: u32 = 0x10FF00;
+
+fn raw_char(b: u8) -> char {
+    ::std::char::from_u32(RAW_BYTES + b as u32).unwrap()
+}
+
+/// The original byte behind a character that stands in for invalid UTF-8
+pub fn raw_byte(c: char) -> Option<u8> {
+    let c = c as u32;
+    if (RAW_BYTES..=RAW_BYTES + 0xFF).contains(&c) {
+        Some((c - RAW_BYTES) as u8)
+    } else {
+        None
+    }
+}
+
+/// Decodes UTF-8 without losing anything. Real characters from the range used for raw bytes
+/// are stored as their raw bytes too, so they can't be mistaken for something else on save.
+fn decode_utf8(mut bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len());
+    loop {
+        let (valid, rest) = match str::from_utf8(bytes) {
+            Ok(s) => (s, &bytes[bytes.len()..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                (str::from_utf8(valid).unwrap(), rest)
+            }
+        };
+        for c in valid.chars() {
+            if raw_byte(c).is_some() {
+                let mut buf = [0; 4];
+                result.extend(c.encode_utf8(&mut buf).bytes().map(raw_char));
+            } else {
+                result.push(c);
+            }
+        }
+        if rest.is_empty() {
+            return result;
+        }
+        let bad = match str::from_utf8(rest) {
+            Err(e) => e.error_len().unwrap_or(rest.len()),
+            Ok(_) => 0
+        };
+        result.extend(rest[..bad].iter().map(|&b| raw_char(b)));
+        bytes = &rest[bad..];
+    }
+}
+
+/// Decodes UTF-16, keeping the bytes of unpaired surrogates and of an odd byte at the end
+/// the same way invalid UTF-8 is kept. Real characters from the range used for raw bytes
+/// get stored as raw bytes too, like in `decode_utf8`.
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> String {
+    let pairs = bytes.chunks_exact(2);
+    let odd = pairs.remainder();
+    let units: Vec<u16> = pairs.map(|c| if big_endian {
+        (c[0] as u16) << 8 | c[1] as u16
+    } else {
+        (c[1] as u16) << 8 | c[0] as u16
+    }).collect();
+    let mut result = String::with_capacity(bytes.len());
+    let mut i = 0;
+    for c in ::std::char::decode_utf16(units.iter().cloned()) {
+        let len = match c {
+            Ok(c) if raw_byte(c).is_none() => {
+                result.push(c);
+                c.len_utf16()
+            },
+            Ok(c) => {
+                result.extend(bytes[i * 2..i * 2 + 4].iter().map(|&b| raw_char(b)));
+                c.len_utf16()
+            },
+            Err(_) => {
+                result.extend(bytes[i * 2..i * 2 + 2].iter().map(|&b| raw_char(b)));
+                1
+            }
+        };
+        i += len;
+    }
+    result.extend(odd.iter().map(|&b| raw_char(b)));
+    result
+}
+
+/// Whether a file is probably not text at all. Like git, this just looks for a NUL byte near the start.
+pub fn looks_binary(bytes: &[u8], charset: &Charset) -> bool {
+    match *charset {
+        Charset::UTF16BE | Charset::UTF16LE => false,
+        _ => bytes.iter().take(8000).any(|&b| b == 0)
+    }
+}
+
+/// Turns the bytes of a file into text, dropping the byte order mark if there is one.
+/// Returns whether there was a byte order mark, so it can be put back on save.
+pub fn decode(bytes: &[u8], charset: &Charset) -> (String, bool) {
+    let text = match *charset {
+        Charset::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        Charset::UTF8 | Charset::UTF8BOM => decode_utf8(bytes),
+        Charset::UTF16BE => decode_utf16(bytes, true),
+        Charset::UTF16LE => decode_utf16(bytes, false),
+    };
+    match *charset {
+        // A plain UTF-8 file that happens to start with a BOM keeps it as text
+        Charset::Latin1 | Charset::UTF8 => (text, false),
+        _ => {
+            if text.starts_with(BOM) {
+                (text[BOM.len_utf8()..].to_string(), true)
+            } else {
+                (text, false)
+            }
+        }
+    }
+}
+
+/// Turns text back into bytes, or gives back the first character the charset can't hold.
+/// Invalid bytes kept from loading a file go back out as they came in.
+pub fn encode(text: &str, charset: &Charset, bom: bool) -> Result<Vec<u8>, char> {
+    let mut result = vec![];
+    match *charset {
+        Charset::Latin1 => {
+            for c in text.chars() {
+                match raw_byte(c) {
+                    Some(b) => result.push(b),
+                    None if (c as u32) < 0x100 => result.push(c as u8),
+                    None => return Err(c)
+                }
+            }
+        },
+        Charset::UTF8 | Charset::UTF8BOM => {
+            let mut buf = [0; 4];
+            if let Charset::UTF8BOM = *charset {
+                result.extend_from_slice(BOM.encode_utf8(&mut buf).as_bytes());
+            }
+            for c in text.chars() {
+                match raw_byte(c) {
+                    Some(b) => result.push(b),
+                    None => result.extend_from_slice(c.encode_utf8(&mut buf).as_bytes())
+                }
+            }
+        },
+        Charset::UTF16BE | Charset::UTF16LE => {
+            let mut buf = [0; 2];
+            let chars = if bom { Some(BOM) } else { None };
+            for c in chars.into_iter().chain(text.chars()) {
+                if let Some(b) = raw_byte(c) {
+                    result.push(b);
+                    continue;
+                }
+                for &u in c.encode_utf16(&mut buf).iter() {
+                    match *charset {
+                        Charset::UTF16BE => result.extend_from_slice(&[(u >> 8) as u8, u as u8]),
+                        _ => result.extend_from_slice(&[u as u8, (u >> 8) as u8]),
+                    }
+                }
+            }
+        },
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let text = "caf\u{E9} \u{1F600}";
+        for charset in [Charset::UTF8, Charset::UTF8BOM, Charset::UTF16BE, Charset::UTF16LE].iter() {
+            for &bom in [false, true].iter() {
+                let bytes = encode(text, charset, bom).unwrap();
+                let (decoded, _) = decode(&bytes, charset);
+                assert_eq!(decoded, text);
+            }
+        }
+        assert_eq!(encode("caf\u{E9}", &Charset::Latin1, false).unwrap(), b"caf\xE9");
+        assert_eq!(encode("caf\u{E9} \u{20AC}5 \u{2014}", &Charset::Latin1, false), Err('\u{20AC}'));
+        assert_eq!(decode(b"caf\xE9", &Charset::Latin1).0, "caf\u{E9}");
+        assert_eq!(encode("a", &Charset::UTF16LE, true).unwrap(), b"\xFF\xFEa\x00");
+        assert_eq!(decode(b"\xFE\xFF\x00a", &Charset::UTF16BE), (String::from("a"), true));
+    }
+
+    #[test]
+    fn invalid_utf8_survives() {
+        let bytes: &[u8] = b"ok \xFF\xC3 \xF4\x8F\xBC\x80 \xE2\x82 done\xE2";
+        let (text, _) = decode(bytes, &Charset::UTF8);
+        assert_eq!(text.chars().filter_map(raw_byte).collect::<Vec<_>>(), b"\xFF\xC3\xF4\x8F\xBC\x80\xE2\x82\xE2");
+        assert!(text.starts_with("ok ") && text.contains(" done"));
+        assert_eq!(encode(&text, &Charset::UTF8, false).unwrap(), bytes);
+        assert!(looks_binary(b"\x7FELF\x00\x01", &Charset::UTF8));
+        assert!(!looks_binary(b"plain text", &Charset::UTF8));
+    }
+
+    #[test]
+    fn invalid_utf16_survives() {
+        // An unpaired surrogate, a real character from the raw byte range, and an odd byte at the end
+        let bytes: &[u8] = b"\x00a\xDC\x00\x00b\xDB\xFF\xDF\x41\x00";
+        let (text, _) = decode(bytes, &Charset::UTF16BE);
+        assert!(text.starts_with('a') && text.contains('b'));
+        assert_eq!(text.chars().filter_map(raw_byte).collect::<Vec<_>>(), b"\xDC\x00\xDB\xFF\xDF\x41\x00");
+        assert_eq!(encode(&text, &Charset::UTF16BE, false).unwrap(), bytes);
+        let swapped: Vec<u8> = bytes.chunks(2).flat_map(|c| c.iter().rev().cloned()).collect();
+        assert_eq!(encode(&decode(&swapped, &Charset::UTF16LE).0, &Charset::UTF16LE, false).unwrap(), swapped);
+    }
+}
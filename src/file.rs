@@ -1,12 +1,20 @@
 use std::fs;
 use std::io;
-use std::io::prelude::*;
 use std::cmp;
 use std::fmt;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use super::terminal::Color;
 use super::indent::Indented;
-use super::config::Config;
+use super::config::{Config, EndOfLine};
+use super::history::{History, EditKind, Snapshot};
+use super::search::Matcher;
+use super::encoding;
+use super::atomic;
+use super::recovery;
+use super::grapheme::{self, GraphemeIndexed, UnicodeSegmentation, Wrap};
 
 pub struct TextChunk {
     pub contents: String,
@@ -54,65 +62,120 @@ impl Ord for Cursor {
     }
 }
 
+/// Splits text into lines at every line ending, along with whichever ending it uses most and whether
+/// any others showed up too. Saving writes every line out with the same ending.
+fn split_lines(text: &str) -> (Vec<String>, Option<EndOfLine>, bool) {
+    let mut lines = vec![];
+    let mut counts = [(0, EndOfLine::Lf), (0, EndOfLine::CrLf), (0, EndOfLine::Cr)];
+    let mut line = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let eol = match c {
+            '\n' => 1,
+            '\r' if chars.peek() == Some(&'\n') => {
+                chars.next();
+                2
+            },
+            '\r' => 3,
+            _ => {
+                line.push(c);
+                continue;
+            }
+        };
+        counts[eol - 1].0 += 1;
+        lines.push(mem::take(&mut line));
+    }
+    if lines.is_empty() || !line.is_empty() {
+        lines.push(line);
+    }
+    let mixed = counts.iter().filter(|&&(n, _)| n > 0).count() > 1;
+    // Ties go to whichever comes first
+    let eol = counts.iter().fold(None, |best: Option<(usize, EndOfLine)>, &(n, eol)| match best {
+        Some((m, _)) if m >= n => best,
+        _ if n > 0 => Some((n, eol)),
+        _ => best
+    }).map(|(_, eol)| eol);
+    (lines, eol, mixed)
+}
+
+/// When a file was last modified and how big it was, to notice someone else changing it
+type DiskStamp = (SystemTime, u64);
+
+fn disk_stamp(path: &str) -> Option<DiskStamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+fn extra_rows(line: &str, wrap: Wrap) -> i32 {
+    line.wrap_points(wrap).len() as i32 - 1
+}
+
 impl Cursor {
-    fn curr_len(&self, lines: &Vec<String>) -> i32 {
-        lines[self.y as usize - 1].len() as i32
+    fn curr_len(&self, lines: &[String]) -> i32 {
+        lines[self.y as usize - 1].grapheme_len() as i32
     }
 
-    fn move_left(&mut self, dim: (i32, i32), lines: &Vec<String>) {
+    /// Which wrapped row of its line the cursor is on, and which column of that row
+    fn position(&self, wrap: Wrap, lines: &[String]) -> (i32, i32) {
+        let (row, col) = lines[self.y as usize - 1].position_of(self.x as usize - 1, wrap);
+        (row as i32, col as i32)
+    }
+
+    fn move_to_position(&mut self, wrap: Wrap, lines: &[String], (row, col): (i32, i32)) {
+        self.x = lines[self.y as usize - 1].index_at(row as usize, col as usize, wrap) as i32 + 1;
+    }
+
+    fn move_left(&mut self, wrap: Wrap, lines: &[String]) {
         if self.x > 1 {
             self.x -= 1;
         } else if self.y > 1 {
-            self.move_up(dim, lines);
+            self.y -= 1;
+            self.y_offset -= extra_rows(&lines[self.y as usize - 1], wrap);
             self.x = self.curr_len(lines) + 1;
         }
     }
 
-    fn move_right(&mut self, dim: (i32, i32), lines: &Vec<String>) {
+    fn move_right(&mut self, wrap: Wrap, lines: &[String]) {
         if self.x <= self.curr_len(lines) {
             self.x += 1;
         } else if self.y < lines.len() as i32 {
-            self.move_down(dim, lines);
+            self.y_offset += extra_rows(&lines[self.y as usize - 1], wrap);
+            self.y += 1;
             self.x = 1;
         }
     }
 
-    fn move_up(&mut self, dim: (i32, i32), lines: &Vec<String>) {
-        if self.x > dim.0 {
-            self.x -= dim.0;
+    fn move_up(&mut self, wrap: Wrap, lines: &[String]) {
+        let (row, col) = self.position(wrap, lines);
+        if row > 0 {
+            self.move_to_position(wrap, lines, (row - 1, col));
         } else if self.y > 1 {
             self.y -= 1;
-            let line_len = self.curr_len(lines);
-            let extra_lines = line_len / dim.0;
-            self.y_offset -= extra_lines;
-            self.x += extra_lines * dim.0;
-            if self.x > line_len + 1 {
-                self.x = line_len + 1;
-            }
+            let extra = extra_rows(&lines[self.y as usize - 1], wrap);
+            self.y_offset -= extra;
+            self.move_to_position(wrap, lines, (extra, col));
         }
     }
 
-    fn move_down(&mut self, dim: (i32, i32), lines: &Vec<String>) {
-        if (self.x - 1) / dim.0 < self.curr_len(lines) / dim.0 {
-            self.x += dim.0;
+    fn move_down(&mut self, wrap: Wrap, lines: &[String]) {
+        let (row, col) = self.position(wrap, lines);
+        let extra = extra_rows(&lines[self.y as usize - 1], wrap);
+        if row < extra {
+            self.move_to_position(wrap, lines, (row + 1, col));
         } else if self.y < lines.len() as i32 {
+            self.y_offset += extra;
             self.y += 1;
-            while self.x > dim.0 {
-                self.x -= dim.0;
-                self.y_offset += 1;
-            }
-        }
-        if self.x > self.curr_len(lines) + 1 {
-            self.x = self.curr_len(lines) + 1;
+            self.move_to_position(wrap, lines, (0, col));
         }
     }
 
-    fn move_home(&mut self, dim: (i32, i32), lines: &Vec<String>, indent_size: u8) {
-        if self.x <= dim.0 {
+    fn move_home(&mut self, wrap: Wrap, lines: &[String], indent: &str) {
+        let (row, _) = self.position(wrap, lines);
+        if row == 0 {
             // TODO make this not hard coded
-            if let Some(s) = lines[self.y as usize - 1].indent_end(indent_size) {
-                if self.x != s as i32 + 1 {
-                    self.x = s as i32 + 1;
+            if let Some(s) = lines[self.y as usize - 1].indent_end(indent) {
+                if self.x != s + 1 {
+                    self.x = s + 1;
                 } else {
                     self.x = 1;
                 }
@@ -120,46 +183,71 @@ impl Cursor {
                 self.x = 1;
             }
         } else {
-            // Depend on truncation here to clamp to lower multiple of screen width
-            self.x = ((self.x - 1) / dim.0) * dim.0 + 1;
+            self.move_to_position(wrap, lines, (row, 0));
         }
     }
 
-    fn move_end(&mut self, dim: (i32, i32), lines: &Vec<String>) {
-        self.x = ((self.x - 1) / dim.0 + 1) * dim.0;
-        if self.x > self.curr_len(lines) + 1 {
-            self.x = self.curr_len(lines) + 1;
-        }
+    fn move_end(&mut self, wrap: Wrap, lines: &[String]) {
+        let (row, _) = self.position(wrap, lines);
+        self.move_to_position(wrap, lines, (row, wrap.width as i32));
     }
 
-    fn project(&self, dim: (i32, i32)) -> Cursor {
+    fn project(&self, wrap: Wrap, lines: &[String]) -> Cursor {
+        let (row, col) = self.position(wrap, lines);
         Cursor {
-            x: (self.x - 1) % dim.0 + 1,
-            y: self.y + (self.x - 1) / dim.0 + self.y_offset,
+            x: col + 1,
+            y: self.y + row + self.y_offset,
             y_offset: 0,
         }
     }
 
-    fn recompute_offset(&mut self, dim: (i32, i32), lines: &Vec<String>) {
+    fn recompute_offset(&mut self, wrap: Wrap, lines: &[String]) {
         self.y_offset = 0;
-        for i in 0..(self.y - 1) {
-            self.y_offset += lines[i as usize].len() as i32 / dim.0;
+        for line in lines.iter().take(self.y as usize - 1) {
+            self.y_offset += extra_rows(line, wrap);
         }
     }
 }
 
 pub struct File {
     pub name: String,
+    /// Whether the file has never been given a path of its own
+    untitled: bool,
     pub lines: Vec<String>,
     pub caret: Cursor,
     selection_start: Option<Cursor>,
     selecting: bool,
     window_top: Cursor,
     last_dim: (i32, i32),
-    misc: String,
+    /// Something the file has to say about the last thing done to it
+    pub message: Option<String>,
     pub display_dirty: bool,
     contents_dirty: bool,
     config: Config,
+    bom: bool,
+    eol: EndOfLine,
+    /// Whether the file had more than one kind of line ending when it was opened
+    mixed_eol: bool,
+    /// Whether the file ended with a line ending when it was opened
+    final_newline: bool,
+    /// Whether the file looked like it wasn't text when it was opened
+    pub binary: bool,
+    pub read_only: bool,
+    disk: Option<DiskStamp>,
+    disk_seen: Option<DiskStamp>,
+    /// Which state the contents are in, so a recovery copy can tell if it's out of date.
+    /// Undo goes back to an old version instead of making a new one.
+    version: u64,
+    /// The newest version handed out so far
+    versions: u64,
+    /// The version that was last saved or loaded, to tell when undo gets back to it
+    saved_version: u64,
+    recovery_version: Option<u64>,
+    /// Where recovery copies go, if there's anywhere for them to go
+    recovery_dir: Option<PathBuf>,
+    history: History,
+    search: Option<Matcher>,
+    search_origin: Cursor,
 }
 
 impl File {
@@ -169,99 +257,282 @@ impl File {
         } else {
             String::from("")
         };
-        format!("{}Caret {}, Top {}, Cursor {} {}", selection_text,
-            self.caret, self.window_top, self.cursor(dim), self.misc
+        let search_text = match self.search_status() {
+            Some(s) => format!("{}, ", s),
+            None => String::from("")
+        };
+        let eol_text = if self.mixed_eol {
+            format!("{} (mixed)", self.eol.name())
+        } else {
+            String::from(self.eol.name())
+        };
+        format!("{}{}Caret {}, Top {}, Cursor {}, {}, {}", search_text, selection_text,
+            self.caret, self.window_top, self.cursor(dim), self.config.charset().name(), eol_text
         )
     }
 
     pub fn empty() -> File {
         File {
             name: String::from("<empty>"),
+            untitled: true,
             lines: vec![String::from("")],
             caret: Cursor { x: 1, y: 1, y_offset: 0 },
             selection_start: None,
             selecting: false,
             window_top: Cursor { x: 1, y: 1, y_offset: 0 },
             last_dim: (0, 0),
-            misc: String::from(""),
+            message: None,
             display_dirty: false,
             contents_dirty: false,
             config: Config::config_for(None),
+            bom: false,
+            eol: EndOfLine::Lf,
+            mixed_eol: false,
+            final_newline: true,
+            binary: false,
+            read_only: false,
+            disk: None,
+            disk_seen: None,
+            version: 0,
+            versions: 0,
+            saved_version: 0,
+            recovery_version: None,
+            recovery_dir: recovery::dir(),
+            history: History::default(),
+            search: None,
+            search_origin: Cursor { x: 1, y: 1, y_offset: 0 },
         }
     }
 
-    pub fn open(path: &str) -> File {
-        let mut lines: Vec<String> = match fs::File::open(path) {
-            Ok(f) => {
-                let f = io::BufReader::new(f);
-                f.lines().map(|r| r.unwrap()).collect()
+    /// Loads a file, or starts a new one if there's nothing there yet
+    pub fn open(path: &str) -> io::Result<File> {
+        let config = Config::config_for(Some(path));
+        let (text, bom, binary, exists) = match fs::read(path) {
+            Ok(bytes) => {
+                let (text, bom) = encoding::decode(&bytes, config.charset());
+                (text, bom, encoding::looks_binary(&bytes, config.charset()), true)
             },
             Err(e) => {
                 if e.kind() == io::ErrorKind::NotFound {
-                    vec![]
+                    (String::new(), false, false, false)
                 } else {
-                    panic!("Could not open file: {}", e);
+                    return Err(e);
                 }
             }
         };
-        if lines.len() == 0 {
-            lines.push(String::from(""));
-        }
-
-        let config = Config::config_for(Some(path));
-        let misc = if config.indent() == "\t" {
-            "Tabs are stupid and MFTE doesn't support them."
-        } else {
-            ""
+        let (lines, detected_eol, mixed_eol) = split_lines(&text);
+        let eol = config.end_of_line().or(detected_eol).unwrap_or(EndOfLine::Lf);
+        // A brand new file gets a newline at the end like a text file should
+        let final_newline = match detected_eol {
+            Some(_) => text.ends_with('\n') || text.ends_with('\r'),
+            None => !exists
         };
 
-        File {
+        Ok(File {
             name: String::from(path),
+            untitled: false,
             lines: lines,
             caret: Cursor { x: 1, y: 1, y_offset: 0 },
             selection_start: None,
             selecting: false,
             window_top: Cursor { x: 1, y: 1, y_offset: 0 },
             last_dim: (0, 0),
-            misc: String::from(misc),
+            message: None,
             display_dirty: false,
             contents_dirty: false,
-            config: Config::config_for(Some(path)),
-        }
+            config,
+            bom,
+            eol,
+            mixed_eol,
+            final_newline,
+            binary,
+            read_only: false,
+            disk: disk_stamp(path),
+            disk_seen: None,
+            version: 0,
+            versions: 0,
+            saved_version: 0,
+            recovery_version: None,
+            recovery_dir: recovery::dir(),
+            history: History::default(),
+            search: None,
+            search_origin: Cursor { x: 1, y: 1, y_offset: 0 },
+        })
     }
 
-    pub fn save(&mut self, path: &str) {
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)
-            .expect("Could not open file");
-        let mut f = io::BufWriter::new(f);
-        let ls = self.config.line_sep();
+    pub fn save(&mut self, path: &str) -> io::Result<()> {
+        if !self.editable() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file is read-only"));
+        }
+        // The buffer only gets trimmed once the trimmed text has made it to disk
+        let trimmed: Option<Vec<String>> = if self.config.trim_trailing_whitespace == Some(true) {
+            Some(self.lines.iter().map(|line| line.trim_end().to_string()).collect())
+        } else {
+            None
+        };
+
+        let final_newline = self.config.insert_final_newline.unwrap_or(self.final_newline);
+        let contents = self.encoded(trimmed.as_ref().unwrap_or(&self.lines), final_newline)?;
+        atomic::write(Path::new(path), &contents)?;
 
-        if self.config.trim_trailing_whitespace {
-            for line in self.lines.iter_mut() {
-                *line = line.trim_right().to_string();
+        if let Some(lines) = trimmed {
+            if lines != self.lines {
+                self.lines = lines;
+                self.version = self.new_version();
             }
         }
 
-        let mut it = self.lines.iter().peekable();
+        self.contents_dirty = false;
+        self.saved_version = self.version;
+        self.mixed_eol = false;
+        self.final_newline = final_newline;
+        self.disk = disk_stamp(path);
+        Ok(())
+    }
+
+    /// The bytes that saving would write out, unless the charset can't hold some of the text
+    fn encoded(&self, lines: &[String], final_newline: bool) -> io::Result<Vec<u8>> {
+        let ls = self.eol.sep();
+        let mut text = String::new();
+        let mut it = lines.iter().peekable();
 
         while it.peek().is_some() {
             let line = it.next().unwrap();
-            write!(f, "{}", line).unwrap();
-            if it.peek().is_some() || self.config.insert_final_newline {
-                write!(f, "{}", ls).unwrap();
+            text.push_str(line);
+            if it.peek().is_some() || final_newline {
+                text.push_str(ls);
             }
         }
 
-        self.contents_dirty = false;
+        let charset = self.config.charset();
+        encoding::encode(&text, charset, self.bom).map_err(|c| io::Error::new(io::ErrorKind::InvalidData,
+            format!("{} (U+{:04X}) can't be saved as {}", c, c as u32, charset.name())))
+    }
+
+    /// Changes with every change
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// A version nothing has had before
+    fn new_version(&mut self) -> u64 {
+        self.versions += 1;
+        self.versions
+    }
+
+    fn mark_changed(&mut self) {
+        self.contents_dirty = true;
+        self.version = self.new_version();
+    }
+
+    /// Keeps a recovery copy of unsaved changes, or cleans it up once they're saved
+    pub fn write_recovery(&mut self) -> io::Result<()> {
+        if self.untitled {
+            return Ok(());
+        }
+        if !self.contents_dirty {
+            self.forget_recovery();
+            return Ok(());
+        }
+        if self.recovery_version != Some(self.version) {
+            let dir = self.recovery_dir.as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "nowhere to keep recovery files"))?;
+            recovery::save(dir, &self.name, &self.encoded(&self.lines, self.final_newline)?)?;
+            self.recovery_version = Some(self.version);
+        }
+        Ok(())
+    }
+
+    pub fn forget_recovery(&mut self) {
+        if self.recovery_version.take().is_some() {
+            self.discard_recovery();
+        }
+    }
+
+    /// Writes out everything right now as `name.mfte-recover`, for when things have gone wrong.
+    /// Files that were never saved need to be given some other name to keep it under.
+    pub fn dump_recovery(&self, name: &str) -> io::Result<PathBuf> {
+        let contents = self.encoded(&self.lines, self.final_newline)?;
+        if let (false, Some(dir)) = (self.untitled, self.recovery_dir.as_ref()) {
+            // Catch the usual recovery copy up too, so reopening the file still offers it
+            let _ = recovery::save(dir, name, &contents);
+        }
+        let path = PathBuf::from(format!("{}.mfte-recover", name));
+        atomic::write(&path, &contents)?;
+        Ok(path)
+    }
+
+    /// Whether there's a recovery copy of this file from some earlier session that went wrong
+    pub fn has_recovery(&self) -> bool {
+        !self.untitled && self.recovery_version.is_none() && self.recovery_copy().is_some()
+    }
+
+    fn recovery_copy(&self) -> Option<PathBuf> {
+        recovery::newer_copy(self.recovery_dir.as_ref()?, &self.name)
+    }
+
+    /// Swaps in the contents of the recovery copy, as an unsaved (and undoable) change
+    pub fn recover(&mut self, dim: (i32, i32)) -> io::Result<()> {
+        let path = self.recovery_copy().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no recovery copy"))?;
+        let bytes = fs::read(path)?;
+        let (text, _) = encoding::decode(&bytes, self.config.charset());
+        let (lines, _, _) = split_lines(&text);
+        let eol = self.eol;
+        self.replace_lines(dim, lines, eol);
+        self.mark_changed();
+        self.recovery_version = Some(self.version);
+        self.display_dirty = true;
+        Ok(())
+    }
+
+    pub fn discard_recovery(&mut self) {
+        if let Some(ref dir) = self.recovery_dir {
+            recovery::remove(dir, &self.name);
+        }
+        self.recovery_version = None;
+    }
+
+    /// Saves under a new name, picking up whatever EditorConfig says about the new path
+    pub fn save_as(&mut self, path: &str) -> io::Result<()> {
+        if !self.is_at(path) {
+            self.forget_recovery();
+        }
+        let config = Config::config_for(Some(path));
+        let old_config = mem::replace(&mut self.config, config);
+        let old_eol = self.eol;
+        if let Some(eol) = self.config.end_of_line() {
+            self.eol = eol;
+        }
+        if let Err(e) = self.save(path) {
+            // Stay where we were if the file never made it to the new place
+            self.config = old_config;
+            self.eol = old_eol;
+            return Err(e);
+        }
+        self.name = String::from(path);
+        self.untitled = false;
+        self.display_dirty = true;
+        Ok(())
+    }
+
+    pub fn has_path(&self) -> bool {
+        !self.untitled
+    }
+
+    /// Whether a path points at the same file this one was loaded from or saved to
+    pub fn is_at(&self, path: &str) -> bool {
+        if self.untitled {
+            return false;
+        }
+        match (fs::canonicalize(&self.name), fs::canonicalize(path)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => self.name == path
+        }
     }
 
     pub fn cursor(&self, dim: (i32, i32)) -> Cursor {
-        let projected_caret = self.caret.project(dim);
-        let projected_top = self.window_top.project(dim);
+        let projected_caret = self.caret.project(self.wrap(dim), &self.lines);
+        let projected_top = self.window_top.project(self.wrap(dim), &self.lines);
         Cursor {
             x: projected_caret.x,
             y: projected_caret.y - projected_top.y + 1,
@@ -273,6 +544,11 @@ impl File {
         format!("{}", self.lines.len()).len() as i32
     }
 
+    /// Whether there are changes that haven't been saved
+    pub fn dirty(&self) -> bool {
+        self.contents_dirty
+    }
+
     pub fn label(&self) -> String {
         let mut result = if self.contents_dirty {
             String::from("*")
@@ -280,74 +556,93 @@ impl File {
             String::from("")
         };
         result.push_str(&self.name);
+        if self.read_only {
+            result.push_str(" [read-only]");
+        }
         result
     }
 
-    fn chunk(&self, line_number: usize, mut line: String, offset: usize, partial: bool) -> Vec<TextChunk> {
-        let mut result = vec![];
-        let mut last = None;
-        let mut fg = Color::Reset;
-        let mut bg = Color::Reset;
-        if let Some(ref sel) = self.selection_start {
+    fn chunk(&self, line_number: usize, line: String, offset: usize, partial: bool, wrap: Wrap) -> Vec<TextChunk> {
+        let selection = self.selection_start.as_ref().map(|sel| {
             let start = cmp::min(sel, &self.caret);
             let end = cmp::max(sel, &self.caret);
-            let sx = start.x as usize - 1;
-            let ex = end.x as usize - 1;
-            let sy = start.y as usize - 1;
-            let ey = end.y as usize - 1;
-            if line_number >= sy && line_number <= ey {
-                if line_number == ey && offset + line.len() >= ex {
-                    last = Some(TextChunk {
-                        contents: line.split_off(ex - cmp::min(ex, offset)),
-                        foreground: Color::Reset,
-                        background: Color::Reset,
-                    });
-                } else if !partial {
-                    // Throw in a space at the end to indicate that the selection includes the newline
-                    line.push(' ');
+            ((start.y as usize - 1, start.x as usize - 1), (end.y as usize - 1, end.x as usize - 1))
+        });
+        let selected = |x: usize| match selection {
+            Some((start, end)) => (line_number, x) >= start && (line_number, x) < end,
+            None => false
+        };
+        let raw_line = &self.lines[line_number];
+        let matches: Vec<(usize, usize)> = match self.search {
+            Some(ref m) => m.find_all(raw_line).into_iter().map(|(start, end)| {
+                (raw_line.grapheme_index(start), raw_line.grapheme_index(end))
+            }).collect(),
+            None => vec![]
+        };
+        let current = self.caret.x as usize - 1;
+        let style = |x: usize| {
+            if selected(x) {
+                return (Color::Black, Color::White);
+            }
+            for &(start, end) in matches.iter() {
+                if x >= start && x < end {
+                    if start == current && line_number == self.caret.y as usize - 1 {
+                        return (Color::Black, Color::White);
+                    }
+                    return (Color::Black, Color::Grey);
                 }
-                if line_number == sy && offset < sx {
-                    let line_length = line.len();
-                    let real_line = line.split_off(cmp::min(line_length, sx - offset));
-                    result.push(TextChunk {
-                        contents: line,
-                        foreground: Color::Reset,
-                        background: Color::Reset,
-                    });
-                    line = real_line;
+            }
+            (Color::Reset, Color::Reset)
+        };
+
+        let mut result: Vec<TextChunk> = vec![];
+        let mut push = |g: &str, (fg, bg): (Color, Color)| {
+            if let Some(last) = result.last_mut() {
+                if last.foreground == fg && last.background == bg {
+                    last.contents.push_str(g);
+                    return;
                 }
-                fg = Color::Black;
-                bg = Color::White;
             }
+            result.push(TextChunk {
+                contents: g.to_string(),
+                foreground: fg,
+                background: bg,
+            });
+        };
+        let mut col = 0;
+        for (i, g) in line.graphemes(true).enumerate() {
+            let w = wrap.width_at(g, col);
+            if g == "\t" {
+                push(&" ".repeat(w), style(offset + i));
+            } else if let Some(e) = grapheme::escape(g) {
+                let (fg, bg) = style(offset + i);
+                push(&e, if bg == Color::Reset { (Color::Grey, bg) } else { (fg, bg) });
+            } else {
+                push(g, style(offset + i));
+            }
+            col += w;
         }
-        result.push(TextChunk {
-            contents: line,
-            foreground: fg,
-            background: bg,
-        });
-        if let Some(c) = last {
-            result.push(c);
+        if !partial && selected(offset + line.grapheme_len()) {
+            // Throw in a space at the end to indicate that the selection includes the newline
+            push(" ", (Color::Black, Color::White));
         }
         result
     }
 
     pub fn chunked_text(&self, dim: (i32, i32)) -> Vec<(Option<u16>, Vec<TextChunk>)> {
         let mut result = vec![];
-        let width = dim.0 as usize;
         let top_y = self.window_top.y as usize - 1;
-        let top_extra = (self.window_top.x - 1) / dim.0;
+        let top_extra = self.window_top.position(self.wrap(dim), &self.lines).0;
         for (line_number, raw_line) in self.lines.iter().enumerate().skip(top_y) {
-            let mut line_start = 0;
-            let mut line_end = cmp::min(raw_line.len(), width);
-            let line = String::from(&raw_line[line_start..line_end]);
-            let chunks = self.chunk(line_number, line, line_start, line_end < raw_line.len());
-            result.push((Some(line_number as u16), chunks));
-            while line_end < raw_line.len() {
-                line_start = line_end;
-                line_end += cmp::min(raw_line.len() - line_end, width);
-                let line = String::from(&raw_line[line_start..line_end]);
-                let chunks = self.chunk(line_number, line, line_start, line_end < raw_line.len());
-                result.push((None, chunks));
+            let wrap = self.wrap(dim);
+            let mut points = raw_line.wrap_points(wrap);
+            let rows = points.len();
+            points.push(raw_line.grapheme_len());
+            for row in 0..rows {
+                let line = String::from(raw_line.grapheme_slice(points[row], points[row + 1]));
+                let chunks = self.chunk(line_number, line, points[row], row + 1 < rows, wrap);
+                let line_number = if row == 0 { Some(line_number as u16) } else { None };
+                result.push((line_number, chunks));
             }
             if result.len() as i32 >= dim.1 + top_extra {
                 break;
@@ -362,20 +657,25 @@ impl File {
         &self.lines[self.caret.y as usize - 1]
     }
 
-    fn tab_width(&self) -> u8 {
-        self.config.indent().len() as u8
+    fn wrap(&self, dim: (i32, i32)) -> Wrap {
+        Wrap {
+            width: dim.0 as usize,
+            tab_width: self.config.tab_width(),
+        }
     }
 
     fn recompute_offsets(&mut self, dim: (i32, i32)) {
         if dim != self.last_dim {
-            self.caret.recompute_offset(dim, &self.lines);
-            self.window_top.recompute_offset(dim, &self.lines);
+            self.caret.recompute_offset(self.wrap(dim), &self.lines);
+            self.window_top.recompute_offset(self.wrap(dim), &self.lines);
             self.last_dim = dim;
         }
     }
 
+    /// Lays everything out again for a new size, keeping the caret on screen
     pub fn refresh(&mut self, dim: (i32, i32)) {
         self.recompute_offsets(dim);
+        self.scroll_to_caret(dim);
         self.display_dirty = true;
     }
 
@@ -414,7 +714,8 @@ impl File {
             let end = cmp::max(sel, &self.caret);
             let mut pos = (*start).clone();
             let mut here = self.lines[pos.y as usize - 1].clone();
-            let mut result = here.split_off(pos.x as usize - 1);
+            let start_byte = here.byte_index(pos.x as usize - 1);
+            let mut result = here.split_off(start_byte);
             pos.x = 1;
             pos.y += 1;
             while pos.y <= end.y {
@@ -423,7 +724,8 @@ impl File {
                 pos.y += 1;
             }
             let rl = result.len();
-            result.split_off(rl - (self.lines[pos.y as usize - 2].len() - (end.x as usize - 1)));
+            let last = &self.lines[pos.y as usize - 2];
+            result.truncate(rl - (last.len() - last.byte_index(end.x as usize - 1)));
             result
         } else {
             String::from("")
@@ -433,9 +735,9 @@ impl File {
     pub fn move_cursor_left(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        self.caret.move_left(dim, &self.lines);
+        self.caret.move_left(self.wrap(dim), &self.lines);
         if self.cursor(dim).y < 1 {
-            self.window_top.move_up(dim, &self.lines);
+            self.window_top.move_up(self.wrap(dim), &self.lines);
             self.display_dirty = true;
         }
     }
@@ -443,9 +745,9 @@ impl File {
     pub fn move_cursor_right(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        self.caret.move_right(dim, &self.lines);
+        self.caret.move_right(self.wrap(dim), &self.lines);
         if self.cursor(dim).y > dim.1 {
-            self.window_top.move_down(dim, &self.lines);
+            self.window_top.move_down(self.wrap(dim), &self.lines);
             self.display_dirty = true;
         }
     }
@@ -453,9 +755,9 @@ impl File {
     pub fn move_cursor_up(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        self.caret.move_up(dim, &self.lines);
+        self.caret.move_up(self.wrap(dim), &self.lines);
         if self.cursor(dim).y < 1 {
-            self.window_top.move_up(dim, &self.lines);
+            self.window_top.move_up(self.wrap(dim), &self.lines);
             self.display_dirty = true;
         }
     }
@@ -463,9 +765,9 @@ impl File {
     pub fn move_cursor_down(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        self.caret.move_down(dim, &self.lines);
+        self.caret.move_down(self.wrap(dim), &self.lines);
         if self.cursor(dim).y > dim.1 {
-            self.window_top.move_down(dim, &self.lines);
+            self.window_top.move_down(self.wrap(dim), &self.lines);
             self.display_dirty = true;
         }
     }
@@ -473,14 +775,14 @@ impl File {
     pub fn move_cursor_home(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        let w = self.tab_width();
-        self.caret.move_home(dim, &self.lines, w);
+        let indent = self.config.indent();
+        self.caret.move_home(self.wrap(dim), &self.lines, &indent);
     }
 
     pub fn move_cursor_end(&mut self, dim: (i32, i32)) {
         self.tweak_selection();
         self.recompute_offsets(dim);
-        self.caret.move_end(dim, &self.lines);
+        self.caret.move_end(self.wrap(dim), &self.lines);
     }
 
     pub fn page_up(&mut self, dim: (i32, i32)) {
@@ -507,23 +809,147 @@ impl File {
         }
         self.caret.x = if col < 1 {
             1
-        } else if col > self.current_line().len() as i32 + 1 {
-            self.current_line().len() as i32 + 1
+        } else if col > self.current_line().grapheme_len() as i32 + 1 {
+            self.current_line().grapheme_len() as i32 + 1
         } else {
             col
         }
     }
 
+    /// Remembers where the caret was so incremental search can start over from there
+    pub fn begin_search(&mut self) {
+        self.search_origin = self.caret.clone();
+    }
+
+    pub fn end_search(&mut self) {
+        if self.search.take().is_some() {
+            self.display_dirty = true;
+        }
+    }
+
+    /// Jumps to the first match at or after where the search started
+    pub fn search(&mut self, dim: (i32, i32), matcher: Matcher) {
+        let unchanged = self.search.as_ref().map(|m| {
+            m.needle() == matcher.needle() && m.is_regex() == matcher.is_regex()
+        });
+        if unchanged.unwrap_or(false) {
+            return;
+        }
+        let origin = self.search_origin.clone();
+        if matcher.needle().is_empty() {
+            if self.search.take().is_some() {
+                self.goto(dim, (origin.y, origin.x));
+                self.scroll_to_caret(dim);
+                self.display_dirty = true;
+            }
+            return;
+        }
+        self.search = Some(matcher);
+        if !self.find_from(dim, (origin.y, origin.x), true) {
+            self.goto(dim, (origin.y, origin.x));
+            self.scroll_to_caret(dim);
+        }
+        self.display_dirty = true;
+    }
+
+    /// Jumps to the next match after the caret, wrapping around at the end of the file
+    pub fn find_next(&mut self, dim: (i32, i32)) -> bool {
+        let from = (self.caret.y, self.caret.x);
+        self.find_from(dim, from, false)
+    }
+
+    /// Jumps to the first match after (or at, if inclusive) a (line, column) position
+    pub fn find_from(&mut self, dim: (i32, i32), from: (i32, i32), inclusive: bool) -> bool {
+        let matches = self.match_positions();
+        let target = matches.iter()
+            .find(|&&p| if inclusive { p >= from } else { p > from })
+            .or_else(|| matches.first())
+            .cloned();
+        match target {
+            Some(target) => {
+                self.goto(dim, target);
+                self.scroll_to_caret(dim);
+                self.display_dirty = true;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// (line, column) of the start of every match, in order
+    fn match_positions(&self) -> Vec<(i32, i32)> {
+        match self.search {
+            Some(ref m) => self.lines.iter().enumerate().flat_map(|(y, line)| {
+                m.find_all(line).into_iter().map(move |(start, _)| (y as i32 + 1, line.grapheme_index(start) as i32 + 1))
+            }).collect(),
+            None => vec![]
+        }
+    }
+
+    /// Replaces the match under the caret, leaving the caret just past the replacement.
+    /// Returns where the match was, how long it used to be, and how long it is now.
+    pub fn replace_match(&mut self, dim: (i32, i32), with: &str) -> Option<((i32, i32), usize, usize)> {
+        if !self.editable() {
+            return None;
+        }
+        let y = self.caret.y;
+        let x = self.current_line().byte_index(self.caret.x as usize - 1);
+        let found = match self.search {
+            Some(ref m) => m.replacement(self.current_line(), x, with),
+            None => None
+        };
+        found.map(|(start, end, text)| {
+            self.begin_edit(EditKind::Other);
+            self.deselect();
+            let (start_g, old_end_g, new_end_g) = {
+                let line = &mut self.lines[y as usize - 1];
+                let start_g = line.grapheme_index(start);
+                let old_end_g = line.grapheme_index(end);
+                line.replace_range(start..end, &text);
+                (start_g, old_end_g, line.grapheme_index(start + text.len()))
+            };
+            self.goto(dim, (y, new_end_g as i32 + 1));
+            self.scroll_to_caret(dim);
+            self.display_dirty = true;
+            self.mark_changed();
+            self.end_edit();
+            ((y, start_g as i32 + 1), old_end_g - start_g, new_end_g - start_g)
+        })
+    }
+
+    /// Whether the caret is sitting at the start of a match
+    pub fn at_match(&self) -> bool {
+        self.match_positions().contains(&(self.caret.y, self.caret.x))
+    }
+
+    /// Whether there's something being searched for that isn't anywhere in the file
+    pub fn search_failed(&self) -> bool {
+        self.search.is_some() && self.match_positions().is_empty()
+    }
+
+    pub fn search_status(&self) -> Option<String> {
+        self.search.as_ref()?;
+        let matches = self.match_positions();
+        if matches.is_empty() {
+            return Some(String::from("No matches"));
+        }
+        let here = (self.caret.y, self.caret.x);
+        Some(match matches.iter().position(|&p| p == here) {
+            Some(i) => format!("Match {} of {}", i + 1, matches.len()),
+            None => format!("{} matches", matches.len())
+        })
+    }
+
     pub fn scroll_up(&mut self, dim: (i32, i32)) {
         for _ in 0..3 {
-            self.window_top.move_up(dim, &self.lines);
+            self.window_top.move_up(self.wrap(dim), &self.lines);
         }
         self.display_dirty = true;
     }
 
     pub fn scroll_down(&mut self, dim: (i32, i32)) {
         for _ in 0..3 {
-            self.window_top.move_down(dim, &self.lines);
+            self.window_top.move_down(self.wrap(dim), &self.lines);
         }
         self.display_dirty = true;
     }
@@ -539,85 +965,292 @@ impl File {
         }
     }
 
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            lines: self.lines.clone(),
+            eol: self.eol,
+            caret: self.caret.clone(),
+            selection_start: self.selection_start.clone(),
+            version: self.version,
+        }
+    }
+
+    fn restore(&mut self, dim: (i32, i32), snapshot: Snapshot) {
+        self.lines = snapshot.lines;
+        self.eol = snapshot.eol;
+        self.caret = snapshot.caret;
+        self.selection_start = snapshot.selection_start;
+        self.selecting = false;
+        if self.window_top.y > self.lines.len() as i32 {
+            self.window_top.y = self.lines.len() as i32;
+        }
+        self.window_top.x = 1;
+        // Force the wrapping offsets to be recomputed for the restored lines
+        self.last_dim = (0, 0);
+        self.recompute_offsets(dim);
+        self.scroll_to_caret(dim);
+        self.display_dirty = true;
+        self.version = snapshot.version;
+        self.contents_dirty = self.version != self.saved_version;
+    }
+
+    fn scroll_to_caret(&mut self, dim: (i32, i32)) {
+        while self.cursor(dim).y < 1 {
+            self.window_top.move_up(self.wrap(dim), &self.lines);
+        }
+        while self.cursor(dim).y > dim.1 {
+            self.window_top.move_down(self.wrap(dim), &self.lines);
+        }
+    }
+
+    /// Switches to the next kind of line ending, and splits up any lines
+    /// that still have stray line endings of other kinds inside them
+    pub fn convert_line_endings(&mut self, dim: (i32, i32)) {
+        if !self.editable() {
+            return;
+        }
+        let lines = self.lines.clone();
+        let eol = self.eol.next();
+        self.replace_lines(dim, lines, eol);
+        self.mixed_eol = false;
+        self.message = Some(format!("Line endings are now {}", self.eol.name()));
+    }
+
+    /// Swaps in a whole new set of lines and line ending as one undoable step, keeping the caret as close as it can
+    fn replace_lines(&mut self, dim: (i32, i32), lines: Vec<String>, eol: EndOfLine) {
+        if lines == self.lines && eol == self.eol {
+            return;
+        }
+        self.begin_edit(EditKind::Other);
+        let mut caret = self.caret.clone();
+        caret.y = cmp::min(caret.y, lines.len() as i32);
+        caret.x = cmp::min(caret.x, lines[caret.y as usize - 1].grapheme_len() as i32 + 1);
+        let version = self.new_version();
+        self.restore(dim, Snapshot {
+            lines,
+            eol,
+            caret,
+            selection_start: None,
+            version,
+        });
+        self.end_edit();
+    }
+
+    /// Reads the file again from disk. Undo brings back what was there before.
+    pub fn reload(&mut self, dim: (i32, i32)) -> io::Result<()> {
+        // Opening a missing file starts a new one, which here would throw away everything
+        fs::metadata(&self.name).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, "no longer exists"),
+            _ => e
+        })?;
+        let fresh = File::open(&self.name)?;
+        self.config = fresh.config;
+        self.bom = fresh.bom;
+        self.mixed_eol = fresh.mixed_eol;
+        self.final_newline = fresh.final_newline;
+        self.binary = fresh.binary;
+        self.disk = fresh.disk;
+        self.replace_lines(dim, fresh.lines, fresh.eol);
+        self.display_dirty = true;
+        self.contents_dirty = false;
+        self.saved_version = self.version;
+        Ok(())
+    }
+
+    /// Whether something else has written to the file since it was opened or saved
+    pub fn changed_on_disk(&self) -> bool {
+        if self.untitled {
+            return false;
+        }
+        match disk_stamp(&self.name) {
+            Some(now) => self.disk != Some(now),
+            None => false
+        }
+    }
+
+    /// Whether the file changed on disk in a way the user hasn't been told about yet
+    pub fn unseen_change_on_disk(&self) -> bool {
+        self.changed_on_disk() && disk_stamp(&self.name) != self.disk_seen
+    }
+
+    /// Stops offering to reload until the file changes on disk again
+    pub fn ignore_change_on_disk(&mut self) {
+        self.disk_seen = disk_stamp(&self.name);
+    }
+
+    /// Whether the file can be changed, complaining if it can't
+    fn editable(&mut self) -> bool {
+        if self.read_only {
+            self.message = Some(String::from("File is read-only"));
+        }
+        !self.read_only
+    }
+
+    fn begin_edit(&mut self, kind: EditKind) {
+        if self.history.begin(kind, &self.caret) {
+            let snapshot = self.snapshot();
+            self.history.push(snapshot);
+        }
+    }
+
+    fn end_edit(&mut self) {
+        if self.history.end(&self.caret) {
+            let unchanged = match self.history.peek() {
+                Some(s) => s.lines == self.lines && s.eol == self.eol,
+                None => false
+            };
+            if unchanged {
+                if let Some(s) = self.history.discard() {
+                    self.version = s.version;
+                    self.contents_dirty = self.version != self.saved_version;
+                }
+            }
+        }
+    }
+
+    /// Makes every edit until the matching `end_group` undo as a single step
+    pub fn begin_group(&mut self) {
+        self.begin_edit(EditKind::Other);
+    }
+
+    pub fn end_group(&mut self) {
+        self.end_edit();
+    }
+
+    pub fn in_group(&self) -> bool {
+        self.history.in_edit()
+    }
+
+    pub fn undo(&mut self, dim: (i32, i32)) {
+        if !self.editable() {
+            return;
+        }
+        let current = self.snapshot();
+        if let Some(s) = self.history.undo(current) {
+            self.restore(dim, s);
+        }
+    }
+
+    pub fn redo(&mut self, dim: (i32, i32)) {
+        if !self.editable() {
+            return;
+        }
+        let current = self.snapshot();
+        if let Some(s) = self.history.redo(current) {
+            self.restore(dim, s);
+        }
+    }
+
     pub fn insert(&mut self, dim: (i32, i32), c: char) {
+        if !self.editable() {
+            return;
+        }
+        self.begin_edit(if c.is_whitespace() { EditKind::Space } else { EditKind::Insert });
         self.delete_selection(dim);
-        {
-            let x = self.caret.x - 1;
+        let target = {
             let line = &mut self.lines[self.caret.y as usize - 1];
-            let pos = if x > line.len() as i32 {
-                self.caret.x = line.len() as i32 - 1;
-                line.len()
-            } else {
-                x as usize
-            };
+            let pos = line.byte_index(self.caret.x as usize - 1);
             line.insert(pos, c);
-        }
+            // A combining character joins the grapheme before it, so the caret may not need to move
+            line.grapheme_index(pos + c.len_utf8()) as i32 + 1
+        };
         self.display_dirty = true;
-        self.contents_dirty = true;
-        self.move_cursor_right(dim);
+        self.mark_changed();
+        if self.caret.x > target {
+            self.caret.x = target;
+        }
+        while self.caret.x < target {
+            self.move_cursor_right(dim);
+        }
+        self.end_edit();
     }
 
     pub fn delete(&mut self, dim: (i32, i32)) {
+        if !self.editable() {
+            return;
+        }
+        self.begin_edit(EditKind::Delete);
         let x = self.caret.x as usize - 1;
         if self.selection_start.is_some() {
             self.delete_selection(dim);
-        } else if x == self.current_line().len() {
+        } else if x >= self.current_line().grapheme_len() {
             let y = self.caret.y as usize - 1;
             if y < self.lines.len() - 1 {
                 let next_line = self.lines.remove(y + 1);
                 self.lines[y].push_str(&next_line);
             }
         } else {
-            let w = self.tab_width();
-            let end = self.current_line().indent_end(w);
+            let indent = self.config.indent();
+            let end = self.current_line().indent_end(&indent);
             let line = &mut self.lines[self.caret.y as usize - 1];
             let mut indented = end.is_some();
             if let Some(s) = end {
-                indented = x as i32 <= s - w as i32;
+                indented = x as i32 <= s - indent.grapheme_len() as i32;
             }
             if indented {
-                line.pop_indentation(w);
+                line.pop_indentation(&indent);
             } else {
-                line.remove(x);
+                let start = line.byte_index(x);
+                let end = line.byte_index(x + 1);
+                line.drain(start..end);
             }
         }
         self.display_dirty = true;
-        self.contents_dirty = true;
+        self.mark_changed();
+        self.end_edit();
     }
 
     pub fn backspace(&mut self, dim: (i32, i32)) {
+        if !self.editable() {
+            return;
+        }
         let x = self.caret.x - 1;
+        self.begin_edit(EditKind::Backspace);
         if self.selection_start.is_some() {
             self.delete_selection(dim);
-            return;
-        }
-        if x - self.tab_width() as i32 >= 0 && x <= self.current_line().indent_end(self.tab_width()).unwrap_or(-1) {
-            self.caret.x -= self.tab_width() as i32;
         } else {
-            self.move_cursor_left(dim);
+            let indent = self.config.indent();
+            let w = indent.grapheme_len() as i32;
+            if x - w >= 0 && x <= self.current_line().indent_end(&indent).unwrap_or(-1) {
+                self.caret.x -= w;
+            } else {
+                self.move_cursor_left(dim);
+            }
+            self.delete(dim);
         }
-        self.delete(dim);
+        self.end_edit();
     }
 
     pub fn tab(&mut self, dim: (i32, i32)) {
-        for _ in 0..(self.tab_width()) {
-            self.insert(dim, ' ')
+        if !self.editable() {
+            return;
+        }
+        self.begin_edit(EditKind::Space);
+        for c in self.config.indent().chars() {
+            self.insert(dim, c)
         }
+        self.end_edit();
     }
 
     pub fn insert_newline(&mut self, dim: (i32, i32), indent: bool) {
+        if !self.editable() {
+            return;
+        }
+        self.begin_edit(EditKind::Newline);
         self.delete_selection(dim);
-        let w = self.tab_width();
-        let (mut after, mut n) = {
+        let unit = self.config.indent();
+        let (mut after, mut n, prefix) = {
             let before = &mut self.lines[self.caret.y as usize - 1];
-            let n = before.indent_end(w);
-            (before.split_off(self.caret.x as usize - 1), n)
+            let n = before.indent_end(&unit);
+            let prefix = String::from(before.grapheme_slice(0, n.unwrap_or(0) as usize));
+            let split = before.byte_index(self.caret.x as usize - 1);
+            (before.split_off(split), n, prefix)
         };
         if !indent {
             n = None;
         }
-        if let Some(n) = n {
-            after.insert_str(0, &" ".repeat(n as usize));
+        if n.is_some() {
+            after.insert_str(0, &prefix);
         }
         self.lines.insert(self.caret.y as usize, after);
         self.move_cursor_right(dim);
@@ -625,18 +1258,20 @@ impl File {
             self.caret.x += n;
         }
         self.display_dirty = true;
-        self.contents_dirty = true;
+        self.mark_changed();
+        self.end_edit();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::prelude::*;
 
     #[test]
     fn load_save_preserves_everything() {
-        let mut f = File::open("README.md");
-        f.save("readme.bak");
+        let mut f = File::open("README.md").unwrap();
+        f.save("readme.bak").unwrap();
         let orig = fs::File::open("README.md").unwrap();
         let new = fs::File::open("readme.bak").unwrap();
         for (b1, b2) in orig.bytes().zip(new.bytes()) {
@@ -647,7 +1282,7 @@ mod tests {
 
     #[test]
     fn selection_on_wrapped_line_going_forward() {
-        let mut f = File::open("README.md");
+        let mut f = File::open("README.md").unwrap();
         f.select();
         f.move_cursor_right((10, 10));
         f.chunked_text((10, 10));
@@ -655,7 +1290,7 @@ mod tests {
 
     #[test]
     fn selection_on_wrapped_line_going_backward() {
-        let mut f = File::open("README.md");
+        let mut f = File::open("README.md").unwrap();
         f.move_cursor_right((10, 10));
         f.select();
         f.move_cursor_left((10, 10));
@@ -664,7 +1299,7 @@ mod tests {
 
     #[test]
     fn selection_on_wrapped_line_going_backward_from_end_of_line() {
-        let mut f = File::open("README.md");
+        let mut f = File::open("README.md").unwrap();
         for _ in 0..f.current_line().len() {
             f.move_cursor_right((10, 10));
         }
@@ -672,4 +1307,346 @@ mod tests {
         f.move_cursor_left((10, 10));
         f.chunked_text((10, 10));
     }
+
+    #[test]
+    fn undo_groups_typing_by_word() {
+        let mut f = File::empty();
+        for c in "foo bar".chars() {
+            f.insert((80, 24), c);
+        }
+        f.undo((80, 24));
+        assert_eq!(f.lines[0], "foo ");
+        f.undo((80, 24));
+        assert_eq!(f.lines[0], "");
+        assert_eq!(f.caret.x, 1);
+        f.redo((80, 24));
+        assert_eq!(f.lines[0], "foo ");
+        assert_eq!(f.caret.x, 5);
+    }
+
+    #[test]
+    fn edits_that_change_nothing_keep_redo() {
+        let mut f = File::empty();
+        f.insert((80, 24), 'a');
+        f.undo((80, 24));
+        f.backspace((80, 24));
+        f.redo((80, 24));
+        assert_eq!(f.lines, vec!["a"]);
+    }
+
+    #[test]
+    fn undoing_back_to_the_save_is_clean() {
+        let dim = (80, 24);
+        let path = ::std::env::temp_dir().join(format!("mfte_undo_clean_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut f = File::empty();
+        f.insert(dim, 'a');
+        f.save(path).unwrap();
+        f.insert_newline(dim, true);
+        f.insert(dim, 'b');
+        assert!(f.dirty());
+        f.undo(dim);
+        f.undo(dim);
+        assert_eq!(f.lines, vec!["a"]);
+        assert!(!f.dirty());
+        f.undo(dim);
+        assert!(f.dirty());
+        f.redo(dim);
+        assert!(!f.dirty());
+        f.redo(dim);
+        f.redo(dim);
+        assert_eq!(f.lines, vec!["a", "b"]);
+        assert!(f.dirty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_deleted_selection() {
+        let mut f = File::empty();
+        for c in "hello".chars() {
+            f.insert((80, 24), c);
+        }
+        f.insert_newline((80, 24), true);
+        for _ in 0..3 {
+            f.select();
+            f.move_cursor_left((80, 24));
+        }
+        f.backspace((80, 24));
+        assert_eq!(f.lines, vec!["hel"]);
+        f.undo((80, 24));
+        assert_eq!(f.lines, vec!["hello", ""]);
+        assert!(f.has_selection());
+    }
+
+    #[test]
+    fn find_wraps_around() {
+        let mut f = File::empty();
+        f.lines = vec![String::from("one fish"), String::from("two fish"), String::from("red")];
+        f.goto((80, 24), (2, 3));
+        f.begin_search();
+        f.search((80, 24), Matcher::new("fish"));
+        assert_eq!((f.caret.y, f.caret.x), (2, 5));
+        assert_eq!(f.search_status(), Some(String::from("Match 2 of 2")));
+        assert!(f.find_next((80, 24)));
+        assert_eq!((f.caret.y, f.caret.x), (1, 5));
+        f.search((80, 24), Matcher::new("fishy"));
+        assert_eq!((f.caret.y, f.caret.x), (2, 3));
+        assert_eq!(f.search_status(), Some(String::from("No matches")));
+    }
+
+    #[test]
+    fn replace_match_is_undoable() {
+        let mut f = File::empty();
+        f.lines = vec![String::from("one fish two fish")];
+        f.begin_search();
+        f.search((80, 24), Matcher::new("fish"));
+        assert_eq!(f.replace_match((80, 24), "cat"), Some(((1, 5), 4, 3)));
+        assert_eq!(f.lines[0], "one cat two fish");
+        assert_eq!(f.caret.x, 8);
+        assert!(!f.at_match());
+        f.undo((80, 24));
+        assert_eq!(f.lines[0], "one fish two fish");
+    }
+
+    #[test]
+    fn shrinking_keeps_the_caret_on_screen() {
+        let mut f = File::empty();
+        f.lines = vec![String::from("x"); 30];
+        f.lines[20] = "wrap ".repeat(10);
+        for _ in 0..20 {
+            f.move_cursor_down((80, 24));
+        }
+        f.move_cursor_end((80, 24));
+        assert_eq!(f.cursor((80, 24)).y, 21);
+        f.refresh((10, 8));
+        let cursor = f.cursor((10, 8));
+        assert!(cursor.y >= 1 && cursor.y <= 8);
+        assert_eq!(f.caret.y, 21);
+    }
+
+    #[test]
+    fn editing_never_splits_graphemes() {
+        let mut f = File::empty();
+        for c in "ae\u{301}\u{1F600}z".chars() {
+            f.insert((80, 24), c);
+        }
+        assert_eq!(f.caret.x, 5);
+        f.move_cursor_left((80, 24));
+        f.backspace((80, 24));
+        assert_eq!(f.lines[0], "ae\u{301}z");
+        f.move_cursor_left((80, 24));
+        f.delete((80, 24));
+        assert_eq!(f.lines[0], "az");
+        f.lines[0] = String::from("\u{65E5}\u{672C}\u{8A9E}");
+        f.caret.x = 2;
+        f.select();
+        f.move_cursor_right((80, 24));
+        assert_eq!(f.selected_text(), "\u{672C}");
+        f.insert_newline((80, 24), false);
+        assert_eq!(f.lines, vec!["\u{65E5}", "\u{8A9E}"]);
+    }
+
+    #[test]
+    fn wide_characters_wrap_by_display_width() {
+        let mut f = File::empty();
+        f.lines = vec![String::from("\u{65E5}\u{672C}\u{8A9E}\u{65E5}\u{672C}\u{8A9E}"), String::from("x")];
+        f.move_cursor_right((5, 10));
+        assert_eq!((f.cursor((5, 10)).x, f.cursor((5, 10)).y), (3, 1));
+        f.move_cursor_down((5, 10));
+        assert_eq!(f.caret.x, 4);
+        assert_eq!((f.cursor((5, 10)).x, f.cursor((5, 10)).y), (3, 2));
+        f.move_cursor_down((5, 10));
+        f.move_cursor_down((5, 10));
+        assert_eq!((f.caret.x, f.caret.y), (2, 2));
+        assert_eq!((f.cursor((5, 10)).x, f.cursor((5, 10)).y), (2, 4));
+        assert_eq!(f.chunked_text((5, 10)).len(), 4);
+    }
+
+    #[test]
+    fn tab_indentation() {
+        let dir = ::std::env::temp_dir().join(format!("mfte_tab_test_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".editorconfig"), "root = true\n[*]\nindent_style = tab\ntab_width = 8\n").unwrap();
+        let mut f = File::open(dir.join("Makefile").to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        f.tab((80, 24));
+        f.insert((80, 24), 'x');
+        assert_eq!(f.lines[0], "\tx");
+        assert_eq!(f.cursor((80, 24)).x, 10);
+        f.insert_newline((80, 24), true);
+        assert_eq!(f.lines[1], "\t");
+        assert_eq!(f.caret.x, 2);
+        f.backspace((80, 24));
+        assert_eq!(f.lines[1], "");
+        f.move_cursor_up((80, 24));
+        f.move_cursor_home((80, 24));
+        assert_eq!(f.caret.x, 2);
+        f.move_cursor_left((80, 24));
+        assert_eq!(f.caret.x, 1);
+    }
+
+    #[test]
+    fn binary_files_round_trip() {
+        let path = ::std::env::temp_dir().join(format!("mfte_binary_{}.bin", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let bytes: &[u8] = b"\x7FELF\x00\xFF\xFE\nok\n";
+        fs::write(path, bytes).unwrap();
+        let mut f = File::open(path).unwrap();
+        assert!(f.binary);
+        assert_eq!(f.cursor((80, 24)).x, 1);
+        f.move_cursor_end((80, 24));
+        assert_eq!(f.cursor((80, 24)).x, 20);
+        f.read_only = true;
+        f.insert((80, 24), 'x');
+        assert_eq!(f.lines[1], "ok");
+        f.read_only = false;
+        f.save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), bytes);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn line_endings_are_detected() {
+        assert_eq!(split_lines("a\r\nb\r\n"), (vec![String::from("a"), String::from("b")], Some(EndOfLine::CrLf), false));
+        assert_eq!(split_lines("a\rb"), (vec![String::from("a"), String::from("b")], Some(EndOfLine::Cr), false));
+        assert_eq!(split_lines("a\r\nb\nc\r\n"), (vec![String::from("a"), String::from("b"), String::from("c")], Some(EndOfLine::CrLf), true));
+        assert_eq!(split_lines("a\n\r\n"), (vec![String::from("a"), String::from("")], Some(EndOfLine::Lf), true));
+        assert_eq!(split_lines(""), (vec![String::from("")], None, false));
+    }
+
+    #[test]
+    fn line_endings_survive_and_convert() {
+        let path = ::std::env::temp_dir().join(format!("mfte_crlf_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "one\r\ntwo\nthree\r\n").unwrap();
+        let mut f = File::open(path).unwrap();
+        assert_eq!(f.lines, vec!["one", "two", "three"]);
+        f.save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+        f.convert_line_endings((80, 24));
+        f.save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"one\rtwo\rthree\r");
+        f.undo((80, 24));
+        f.save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unconfigured_files_keep_their_shape() {
+        let path = ::std::env::temp_dir().join(format!("mfte_final_newline_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "trailing  \nno newline").unwrap();
+        File::open(path).unwrap().save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"trailing  \nno newline");
+        fs::remove_file(path).unwrap();
+        File::open(path).unwrap().save(path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_saves_leave_the_buffer_alone() {
+        let dir = ::std::env::temp_dir();
+        let mut f = File::empty();
+        f.lines = vec![String::from("a  ")];
+        f.config.trim_trailing_whitespace = Some(true);
+        assert!(f.save(dir.join("mfte_no_such_dir/x.txt").to_str().unwrap()).is_err());
+        assert_eq!(f.lines, vec!["a  "]);
+        let path = dir.join(format!("mfte_trim_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        f.save(path).unwrap();
+        assert_eq!(f.lines, vec!["a"]);
+        assert_eq!(fs::read(path).unwrap(), b"a\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn save_as_moves_the_file() {
+        let dir = ::std::env::temp_dir();
+        let path = dir.join(format!("mfte_save_as_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut f = File::empty();
+        assert!(!f.has_path());
+        f.insert((80, 24), 'x');
+        f.save_as(path).unwrap();
+        assert!(f.has_path());
+        assert_eq!(f.name, path);
+        assert!(f.is_at(path));
+        assert!(!f.is_at("README.md"));
+        assert_eq!(fs::read(path).unwrap(), b"x\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload_notices_changes_on_disk() {
+        let path = ::std::env::temp_dir().join(format!("mfte_reload_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "one\ntwo\n").unwrap();
+        let mut f = File::open(path).unwrap();
+        f.goto((80, 24), (2, 3));
+        assert!(!f.changed_on_disk());
+        fs::write(path, "one\ntwo\nthree\n").unwrap();
+        assert!(f.changed_on_disk() && f.unseen_change_on_disk());
+        f.ignore_change_on_disk();
+        assert!(f.changed_on_disk() && !f.unseen_change_on_disk());
+        f.reload((80, 24)).unwrap();
+        assert_eq!(f.lines.len(), 3);
+        assert_eq!((f.caret.y, f.caret.x), (2, 3));
+        assert!(!f.changed_on_disk() && !f.dirty());
+        f.undo((80, 24));
+        assert_eq!(f.lines.len(), 2);
+        fs::remove_file(path).unwrap();
+        assert!(f.reload((80, 24)).is_err());
+        assert_eq!(f.lines.len(), 2);
+        assert!(f.dirty());
+    }
+
+    /// Opens a file that keeps its recovery copies in a directory of its own
+    fn open_recoverable(path: &str, dir: &Path) -> File {
+        let mut f = File::open(path).unwrap();
+        f.recovery_dir = Some(dir.to_path_buf());
+        f
+    }
+
+    #[test]
+    fn unsaved_changes_can_be_recovered() {
+        let dir = ::std::env::temp_dir().join(format!("mfte_state_recover_{}", ::std::process::id()));
+        let path = ::std::env::temp_dir().join(format!("mfte_recover_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "before\n").unwrap();
+        let mut f = open_recoverable(path, &dir);
+        f.move_cursor_end((80, 24));
+        f.insert((80, 24), '!');
+        f.write_recovery().unwrap();
+
+        let mut g = open_recoverable(path, &dir);
+        assert!(g.has_recovery());
+        g.recover((80, 24)).unwrap();
+        assert_eq!(g.lines[0], "before!");
+        assert!(g.dirty());
+        g.save(path).unwrap();
+        g.write_recovery().unwrap();
+        assert!(!open_recoverable(path, &dir).has_recovery());
+        fs::remove_file(path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dumps_land_next_to_the_file() {
+        let dir = ::std::env::temp_dir().join(format!("mfte_state_dump_{}", ::std::process::id()));
+        let path = ::std::env::temp_dir().join(format!("mfte_dump_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "saved\n").unwrap();
+        let mut f = open_recoverable(path, &dir);
+        f.insert((80, 24), '#');
+        let dump = f.dump_recovery(path).unwrap();
+        assert_eq!(dump, Path::new(&format!("{}.mfte-recover", path)));
+        assert_eq!(fs::read(&dump).unwrap(), b"#saved\n");
+        assert_eq!(fs::read(path).unwrap(), b"saved\n");
+        assert!(open_recoverable(path, &dir).has_recovery());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_file(dump).unwrap();
+        fs::remove_file(path).unwrap();
+    }
 }
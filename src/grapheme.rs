@@ -0,0 +1,177 @@
+extern crate unicode_segmentation;
+extern crate unicode_width;
+pub use self::unicode_segmentation::UnicodeSegmentation;
+use self::unicode_width::UnicodeWidthStr;
+use super::encoding;
+
+/// How many terminal columns a grapheme (or any other text) takes up
+pub fn display_width(text: &str) -> usize {
+    text.width()
+}
+
+/// How a grapheme that can't be drawn as-is shows up instead: invalid bytes and
+/// control characters (other than tabs) become `\xNN`
+pub fn escape(g: &str) -> Option<String> {
+    let mut chars = g.chars();
+    let first = chars.next()?;
+    let byte = match encoding::raw_byte(first) {
+        Some(b) => b,
+        None if first != '\t' && (first < ' ' || first == '\u{7F}') => first as u8,
+        None => return None
+    };
+    Some(format!("\\x{:02X}{}", byte, chars.as_str()))
+}
+
+/// How lines get laid out on screen
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Wrap {
+    pub width: usize,
+    pub tab_width: usize,
+}
+
+impl Wrap {
+    /// How wide a grapheme is when drawn starting at a column, since tabs stretch to the next tab stop
+    pub fn width_at(&self, g: &str, col: usize) -> usize {
+        if g == "\t" {
+            let tab_width = if self.tab_width > 0 { self.tab_width } else { 1 };
+            tab_width - col % tab_width
+        } else if let Some(e) = escape(g) {
+            display_width(&e)
+        } else {
+            display_width(g)
+        }
+    }
+}
+
+/// Lets a line be indexed by user-perceived characters instead of bytes
+pub trait GraphemeIndexed {
+    fn grapheme_len(&self) -> usize;
+    fn byte_index(&self, grapheme: usize) -> usize;
+    fn grapheme_index(&self, byte: usize) -> usize;
+    fn grapheme_slice(&self, start: usize, end: usize) -> &str;
+    fn wrap_points(&self, wrap: Wrap) -> Vec<usize>;
+    fn position_of(&self, grapheme: usize, wrap: Wrap) -> (usize, usize);
+    fn index_at(&self, row: usize, col: usize, wrap: Wrap) -> usize;
+}
+
+impl GraphemeIndexed for str {
+    fn grapheme_len(&self) -> usize {
+        self.graphemes(true).count()
+    }
+
+    /// Where the nth grapheme starts, or the end of the string if there aren't that many
+    fn byte_index(&self, grapheme: usize) -> usize {
+        self.grapheme_indices(true).nth(grapheme).map(|(i, _)| i).unwrap_or(self.len())
+    }
+
+    /// How many graphemes start before a byte offset, so an offset in the middle
+    /// of a grapheme rounds up to the one after it
+    fn grapheme_index(&self, byte: usize) -> usize {
+        self.grapheme_indices(true).take_while(|&(i, _)| i < byte).count()
+    }
+
+    fn grapheme_slice(&self, start: usize, end: usize) -> &str {
+        &self[self.byte_index(start)..self.byte_index(end)]
+    }
+
+    /// The grapheme each screen row starts at when the line is wrapped.
+    /// A line that exactly fills its last row gets an empty row after it for the caret to sit in.
+    fn wrap_points(&self, wrap: Wrap) -> Vec<usize> {
+        let mut result = vec![0];
+        let mut col = 0;
+        for (i, g) in self.graphemes(true).enumerate() {
+            if col + wrap.width_at(g, col) > wrap.width && col > 0 {
+                result.push(i);
+                col = 0;
+            }
+            col += wrap.width_at(g, col);
+        }
+        if col >= wrap.width {
+            result.push(self.grapheme_len());
+        }
+        result
+    }
+
+    /// The (row, column) on screen of the nth grapheme, both counting from zero
+    fn position_of(&self, grapheme: usize, wrap: Wrap) -> (usize, usize) {
+        let points = self.wrap_points(wrap);
+        let row = points.iter().rposition(|&p| p <= grapheme).unwrap_or(0);
+        let col = self.graphemes(true)
+            .skip(points[row])
+            .take(grapheme - points[row])
+            .fold(0, |col, g| col + wrap.width_at(g, col));
+        (row, col)
+    }
+
+    /// The grapheme drawn at a (row, column) on screen, or the closest one in that row
+    fn index_at(&self, row: usize, col: usize, wrap: Wrap) -> usize {
+        let points = self.wrap_points(wrap);
+        let row = if row < points.len() { row } else { points.len() - 1 };
+        // The last spot in a row that isn't the last is just before the next row starts
+        let end = match points.get(row + 1) {
+            Some(&p) => p - 1,
+            None => self.grapheme_len()
+        };
+        let mut result = points[row];
+        let mut here = 0;
+        for g in self.graphemes(true).skip(points[row]) {
+            let w = wrap.width_at(g, here);
+            if result >= end || here + w > col {
+                break;
+            }
+            here += w;
+            result += 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexes_by_grapheme() {
+        let s = "ae\u{301}\u{1F600}x";
+        assert_eq!(s.grapheme_len(), 4);
+        assert_eq!(s.byte_index(2), 4);
+        assert_eq!(s.byte_index(9), s.len());
+        assert_eq!(s.grapheme_index(2), 2);
+        assert_eq!(s.grapheme_index(4), 2);
+        assert_eq!(s.grapheme_slice(1, 3), "e\u{301}\u{1F600}");
+    }
+
+    #[test]
+    fn wraps_by_display_width() {
+        let wrap = |width| Wrap { width, tab_width: 4 };
+        let s = "ab\u{65E5}\u{672C}c";
+        assert_eq!(s.wrap_points(wrap(4)), vec![0, 3]);
+        assert_eq!(s.wrap_points(wrap(3)), vec![0, 2, 3, 5]);
+        assert_eq!(s.position_of(2, wrap(4)), (0, 2));
+        assert_eq!(s.position_of(3, wrap(4)), (1, 0));
+        assert_eq!(s.position_of(5, wrap(4)), (1, 3));
+        assert_eq!(s.index_at(1, 1, wrap(4)), 3);
+        assert_eq!(s.index_at(0, 9, wrap(4)), 2);
+        assert_eq!("abc".wrap_points(wrap(3)), vec![0, 3]);
+        assert_eq!("e\u{301}".position_of(1, wrap(80)), (0, 1));
+    }
+
+    #[test]
+    fn tabs_stretch_to_tab_stops() {
+        let wrap = Wrap { width: 80, tab_width: 4 };
+        assert_eq!("\tx".position_of(1, wrap), (0, 4));
+        assert_eq!("ab\tx".position_of(3, wrap), (0, 4));
+        assert_eq!("ab\tx".index_at(0, 3, wrap), 2);
+        assert_eq!("ab\tx".wrap_points(Wrap { width: 5, tab_width: 4 }), vec![0, 4]);
+    }
+
+    #[test]
+    fn unprintables_are_escaped() {
+        let wrap = Wrap { width: 80, tab_width: 4 };
+        assert_eq!(escape("\u{10FFFF}"), Some(String::from("\\xFF")));
+        assert_eq!(escape("\u{1B}"), Some(String::from("\\x1B")));
+        assert_eq!(escape("\t"), None);
+        assert_eq!(escape("a"), None);
+        assert_eq!("\u{0}x".position_of(1, wrap), (0, 4));
+    }
+}
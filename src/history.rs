@@ -0,0 +1,253 @@
+use std::cmp;
+use std::mem;
+
+use super::config::EndOfLine;
+use super::file::Cursor;
+
+/// What kind of change an edit makes, so runs of similar edits can be grouped
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditKind {
+    Insert,
+    Space,
+    Newline,
+    Delete,
+    Backspace,
+    Other,
+}
+
+impl EditKind {
+    // Typing a word and then the space after it is one step, but the next word is another
+    fn continues(self, next: EditKind) -> bool {
+        match (self, next) {
+            (EditKind::Other, _) | (_, EditKind::Other) => false,
+            (EditKind::Newline, _) | (_, EditKind::Newline) => false,
+            (EditKind::Insert, EditKind::Space) => true,
+            (a, b) => a == b
+        }
+    }
+}
+
+/// How many steps back undo can go. The oldest ones get forgotten past that.
+const MAX_UNDO: usize = 1000;
+
+/// Everything needed to put a file back the way it was
+#[derive(Clone)]
+pub struct Snapshot {
+    pub lines: Vec<String>,
+    pub eol: EndOfLine,
+    pub caret: Cursor,
+    pub selection_start: Option<Cursor>,
+    /// The file's version at the time, so going back to what was saved can count as saved
+    pub version: u64,
+}
+
+/// A snapshot that only keeps the lines that differ from the one next to it in the history.
+/// Keeping every line for every step would mean holding on to the whole file hundreds of times over.
+struct Change {
+    /// How many lines at the start and the end are the same as next door
+    same_start: usize,
+    same_end: usize,
+    lines: Vec<String>,
+    eol: EndOfLine,
+    caret: Cursor,
+    selection_start: Option<Cursor>,
+    version: u64,
+}
+
+impl Change {
+    fn between(mut snapshot: Snapshot, next: &[String]) -> Change {
+        let lines = &snapshot.lines;
+        let same_start = lines.iter().zip(next).take_while(|&(a, b)| a == b).count();
+        let most = cmp::min(lines.len(), next.len()) - same_start;
+        let same_end = lines.iter().rev().zip(next.iter().rev()).take(most).take_while(|&(a, b)| a == b).count();
+        let end = lines.len() - same_end;
+        Change {
+            same_start,
+            same_end,
+            lines: snapshot.lines.drain(same_start..end).collect(),
+            eol: snapshot.eol,
+            caret: snapshot.caret,
+            selection_start: snapshot.selection_start,
+            version: snapshot.version,
+        }
+    }
+
+    fn apply(self, next: &[String]) -> Snapshot {
+        let mut lines = next[..self.same_start].to_vec();
+        lines.extend(self.lines);
+        lines.extend_from_slice(&next[next.len() - self.same_end..]);
+        Snapshot {
+            lines,
+            eol: self.eol,
+            caret: self.caret,
+            selection_start: self.selection_start,
+            version: self.version,
+        }
+    }
+}
+
+/// Undo and redo, kept as `Change`s that each only make sense next to the one above them.
+/// The top of `redo` goes with the file as it is now, and so does `newest`, the one
+/// snapshot that gets kept whole since the edit after it might still be going on.
+pub struct History {
+    newest: Option<Snapshot>,
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+    /// What `push` cleared out of `redo`, in case the edit gets discarded
+    cleared: Vec<Change>,
+    last: Option<(EditKind, Cursor)>,
+    kind: EditKind,
+    depth: u32,
+    fresh: bool,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            newest: None,
+            undo: vec![],
+            redo: vec![],
+            cleared: vec![],
+            last: None,
+            kind: EditKind::Other,
+            depth: 0,
+            fresh: false,
+        }
+    }
+}
+
+impl History {
+    /// Starts an edit, returning true if the caller should push a snapshot first.
+    /// Edits nest; only the outermost one counts.
+    pub fn begin(&mut self, kind: EditKind, caret: &Cursor) -> bool {
+        self.depth += 1;
+        if self.depth > 1 {
+            return false;
+        }
+        self.kind = kind;
+        self.fresh = match self.last {
+            Some((last_kind, ref last_caret)) => !(last_kind.continues(kind) && last_caret == caret),
+            None => true
+        };
+        self.fresh
+    }
+
+    /// Ends an edit, returning true if the outermost edit just finished and
+    /// pushed a snapshot of its own.
+    pub fn end(&mut self, caret: &Cursor) -> bool {
+        self.depth -= 1;
+        if self.depth > 0 {
+            return false;
+        }
+        self.last = Some((self.kind, caret.clone()));
+        self.fresh
+    }
+
+    /// Whether an edit has begun and not ended yet
+    pub fn in_edit(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.keep(snapshot);
+        self.cleared = mem::take(&mut self.redo);
+    }
+
+    /// Makes `snapshot` the newest thing to undo to, shrinking the one it replaces
+    fn keep(&mut self, snapshot: Snapshot) {
+        if let Some(newest) = self.newest.take() {
+            self.undo.push(Change::between(newest, &snapshot.lines));
+            if self.undo.len() > MAX_UNDO {
+                self.undo.remove(0);
+            }
+        }
+        self.newest = Some(snapshot);
+    }
+
+    /// Throws away the newest snapshot, for edits that turned out to change nothing,
+    /// and brings back whatever could be redone before it
+    pub fn discard(&mut self) -> Option<Snapshot> {
+        self.last = None;
+        self.redo = mem::take(&mut self.cleared);
+        let result = self.newest.take();
+        if let Some(ref s) = result {
+            self.newest = self.undo.pop().map(|c| c.apply(&s.lines));
+        }
+        result
+    }
+
+    pub fn peek(&self) -> Option<&Snapshot> {
+        self.newest.as_ref()
+    }
+
+    pub fn undo(&mut self, current: Snapshot) -> Option<Snapshot> {
+        self.last = None;
+        let result = self.newest.take();
+        if let Some(ref s) = result {
+            self.newest = self.undo.pop().map(|c| c.apply(&s.lines));
+            self.redo.push(Change::between(current, &s.lines));
+        }
+        result
+    }
+
+    pub fn redo(&mut self, current: Snapshot) -> Option<Snapshot> {
+        self.last = None;
+        let result = self.redo.pop().map(|c| c.apply(&current.lines));
+        if result.is_some() {
+            self.keep(current);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::file::File;
+
+    fn caret() -> Cursor {
+        File::empty().caret
+    }
+
+    fn snapshot(lines: &[&str]) -> Snapshot {
+        Snapshot {
+            lines: lines.iter().map(|l| l.to_string()).collect(),
+            eol: EndOfLine::Lf,
+            caret: caret(),
+            selection_start: None,
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn only_changed_lines_are_kept() {
+        let before = snapshot(&["a", "b", "c", "d"]);
+        let after = snapshot(&["a", "x", "y", "d"]).lines;
+        let change = Change::between(before.clone(), &after);
+        assert_eq!((change.same_start, change.same_end), (1, 1));
+        assert_eq!(change.lines, vec!["b", "c"]);
+        assert_eq!(change.apply(&after).lines, before.lines);
+
+        let before = snapshot(&["a", "a"]);
+        let after = snapshot(&["a"]).lines;
+        let change = Change::between(before.clone(), &after);
+        assert_eq!(change.same_start + change.same_end, 1);
+        assert_eq!(change.apply(&after).lines, before.lines);
+    }
+
+    #[test]
+    fn history_stops_somewhere() {
+        let mut h = History::default();
+        let caret = caret();
+        for i in 0..MAX_UNDO + 10 {
+            h.begin(EditKind::Other, &caret);
+            h.push(snapshot(&[&i.to_string()]));
+            h.end(&caret);
+        }
+        let mut steps = 0;
+        while h.undo(snapshot(&["now"])).is_some() {
+            steps += 1;
+        }
+        assert_eq!(steps, MAX_UNDO + 1);
+    }
+}
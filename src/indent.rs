@@ -1,25 +1,50 @@
+use super::grapheme::GraphemeIndexed;
+
 pub trait Indented {
-    fn indent_end(&self, indent_size: u8) -> Option<i32>;
-    fn pop_indentation(&mut self, indent_size: u8);
+    fn indent_end(&self, indent: &str) -> Option<i32>;
+    fn pop_indentation(&mut self, indent: &str);
 }
 
 impl Indented for String {
-    fn indent_end(&self, indent_size: u8) -> Option<i32> {
-        let mut leading_spaces = self.len() - self.trim_left().len();
-        if leading_spaces % (indent_size as usize) > 0 {
-            leading_spaces -= leading_spaces % (indent_size as usize);
+    /// Where the last whole level of indentation ends, in graphemes.
+    /// `indent` is one level, like "\t" or four spaces.
+    fn indent_end(&self, indent: &str) -> Option<i32> {
+        if indent.is_empty() {
+            return None;
+        }
+        let mut levels = 0;
+        let mut rest = &self[..];
+        while rest.starts_with(indent) {
+            rest = &rest[indent.len()..];
+            levels += 1;
         }
-        if leading_spaces > 0 {
-            Some(leading_spaces as i32)
+        if levels > 0 {
+            Some((levels * indent.grapheme_len()) as i32)
         } else {
             None
         }
     }
 
-    fn pop_indentation(&mut self, indent_size: u8) {
-        if let Some(end) = self.indent_end(indent_size) {
-            let end = end as usize;
-            self.drain((end - indent_size as usize)..end);
+    fn pop_indentation(&mut self, indent: &str) {
+        if let Some(end) = self.indent_end(indent) {
+            let start = self.byte_index(end as usize - indent.grapheme_len());
+            let end = self.byte_index(end as usize);
+            self.drain(start..end);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indentation_comes_in_whole_levels() {
+        assert_eq!(String::from("      x").indent_end("    "), Some(4));
+        assert_eq!(String::from("\t\t x").indent_end("\t"), Some(2));
+        assert_eq!(String::from("  x").indent_end("    "), None);
+        let mut s = String::from("\t\tx");
+        s.pop_indentation("\t");
+        assert_eq!(s, "\tx");
+    }
+}
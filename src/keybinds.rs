@@ -1,10 +1,15 @@
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use super::terminal::Key;
 
 const DEFAULT_KEYBINDS: &'static str = r#"^Q: Quit
 ^W: CloseFile
 ^O: OpenFile
 ^S: SaveFile
+^E: SaveAs
 ^X: Cut
 ^C: Copy
 ^V: Paste
@@ -12,9 +17,14 @@ const DEFAULT_KEYBINDS: &'static str = r#"^Q: Quit
 ^H: FindReplace
 ^Z: Undo
 ^Y: Redo
-^R: Refresh
+^R: Reload
+^U: Refresh
 ^T: NewTab
 ^G: Goto
+^L: LineEndings
+^K: RecordMacro
+^P: PlayMacro
+M-r: ToggleRegex
 "#;
 
 macro_attr! {
@@ -24,6 +34,7 @@ macro_attr! {
         CloseFile,
         OpenFile,
         SaveFile,
+        SaveAs,
         Cut,
         Copy,
         Paste,
@@ -32,11 +43,17 @@ macro_attr! {
         Undo,
         Redo,
         Refresh,
+        Reload,
         NewTab,
         Goto,
+        LineEndings,
+        RecordMacro,
+        PlayMacro,
+        ToggleRegex,
     }
 }
 
+#[derive(Clone)]
 pub struct KeybindTable {
     table: HashMap<Key, Command>
 }
@@ -48,14 +65,7 @@ impl KeybindTable {
 
     pub fn entries(&self) -> Vec<(String, String)> {
         self.table.iter().map(|(key, command)| {
-            let key = match key {
-                &Key::Ctrl(ref k) => match **k {
-                    Key::Char(ref k) => format!("^{}", *k).to_uppercase(),
-                    _ => String::from("???")
-                },
-                _ => String::from("???")
-            };
-            (key, format!("{}", command))
+            (encode_key_spec(key), format!("{}", command))
         }).collect()
     }
 }
@@ -66,19 +76,202 @@ impl Default for KeybindTable {
     }
 }
 
-fn decode_key_spec(spec: &str) -> Option<Key> {
-    if spec.len() != 2 {
-        println!("Bad key specifier: {}", spec);
-        return None;
+/// Takes a modifier like `M-` off the front of a key specifier, ignoring case,
+/// as long as there's still a key left after it
+fn strip_modifier<'a>(spec: &'a str, modifier: &str) -> Option<&'a str> {
+    let n = modifier.len();
+    if spec.len() > n && spec.is_char_boundary(n) && spec[..n].eq_ignore_ascii_case(modifier) {
+        Some(&spec[n..])
+    } else {
+        None
     }
-    let (modifier, key) = spec.split_at(1);
-    let modifier = modifier.chars().next().unwrap();
-    let key = key.chars().next().unwrap();
-    if modifier != '^' {
-        println!("Bad key specifier: {}", spec);
-        return None;
+}
+
+/// Reads a key specifier like `^S`, `M-x`, `S-Tab`, `^M-PgDn` or `F5`
+fn decode_key_spec(spec: &str) -> Result<Key, String> {
+    let bad = || format!("Bad key specifier: {}", spec);
+    let (mut ctrl, mut alt, mut shift) = (false, false, false);
+    let mut rest = spec;
+    loop {
+        let flag = if let Some(r) = strip_modifier(rest, "^") {
+            rest = r;
+            &mut ctrl
+        } else if let Some(r) = strip_modifier(rest, "M-").or_else(|| strip_modifier(rest, "Alt-")) {
+            rest = r;
+            &mut alt
+        } else if let Some(r) = strip_modifier(rest, "S-") {
+            rest = r;
+            &mut shift
+        } else {
+            break;
+        };
+        if *flag {
+            return Err(bad());
+        }
+        *flag = true;
+    }
+    let mut chars = rest.chars();
+    let mut key = match (chars.next(), chars.next()) {
+        // Terminals can't tell Ctrl+S from Ctrl+Shift+S, but Shift does make letters capital
+        (Some(c), None) if shift => Key::Char(c.to_uppercase().next().unwrap_or(c)),
+        (Some(c), None) if ctrl => Key::Char(c.to_lowercase().next().unwrap_or(c)),
+        (Some(c), None) => Key::Char(c),
+        _ => match rest.to_lowercase().as_str() {
+            "tab" => Key::Char('\t'),
+            "enter" | "return" => Key::Char('\n'),
+            "space" => Key::Char(' '),
+            "backspace" | "bksp" => Key::Backspace,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "pgup" | "pageup" => Key::PageUp,
+            "pgdn" | "pagedown" => Key::PageDown,
+            "del" | "delete" => Key::Delete,
+            "ins" | "insert" => Key::Insert,
+            "esc" | "escape" => Key::Esc,
+            "null" => Key::Null,
+            f if f.starts_with('f') => match f[1..].parse() {
+                Ok(n) if (1..=24).contains(&n) => Key::F(n),
+                _ => return Err(bad())
+            },
+            _ => return Err(bad())
+        }
+    };
+    // Modifiers always nest as Ctrl(Alt(Shift(key))), whatever order they were written in
+    if shift {
+        key = Key::Shift(Box::new(key));
+    }
+    if alt {
+        key = Key::Alt(Box::new(key));
+    }
+    if ctrl {
+        key = Key::Ctrl(Box::new(key));
+    }
+    Ok(key)
+}
+
+/// Writes a key the way `decode_key_spec` reads it
+fn encode_key_spec(key: &Key) -> String {
+    let mut spec = String::new();
+    let mut key = key;
+    let mut ctrl = false;
+    loop {
+        match *key {
+            Key::Ctrl(ref k) => {
+                spec.push('^');
+                ctrl = true;
+                key = k;
+            },
+            Key::Alt(ref k) => {
+                spec.push_str("M-");
+                key = k;
+            },
+            Key::Shift(ref k) => {
+                spec.push_str("S-");
+                key = k;
+            },
+            _ => break
+        }
+    }
+    let name = match *key {
+        Key::Char('\t') => "Tab",
+        Key::Char('\n') => "Enter",
+        Key::Char(' ') => "Space",
+        Key::Char(c) => {
+            if ctrl {
+                spec.extend(c.to_uppercase());
+            } else {
+                spec.push(c);
+            }
+            return spec;
+        },
+        Key::F(n) => {
+            spec.push_str(&format!("F{}", n));
+            return spec;
+        },
+        Key::Backspace => "Backspace",
+        Key::Left => "Left",
+        Key::Right => "Right",
+        Key::Up => "Up",
+        Key::Down => "Down",
+        Key::Home => "Home",
+        Key::End => "End",
+        Key::PageUp => "PgUp",
+        Key::PageDown => "PgDn",
+        Key::Delete => "Del",
+        Key::Insert => "Ins",
+        Key::Esc => "Esc",
+        Key::Null => "Null",
+        Key::Ctrl(_) | Key::Alt(_) | Key::Shift(_) => unreachable!(),
+    };
+    spec.push_str(name);
+    spec
+}
+
+/// Where the user's own keybindings live: `$XDG_CONFIG_HOME/mfte/keys`, which is usually `~/.config/mfte/keys`
+#[cfg(not(windows))]
+pub fn user_keys_path() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref d) if !d.is_empty() => Some(PathBuf::from(d)),
+        _ => env::var_os("HOME").map(|h| Path::new(&h).join(".config"))
+    }.map(|d| d.join("mfte").join("keys"))
+}
+
+#[cfg(windows)]
+pub fn user_keys_path() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|d| Path::new(&d).join("mfte").join("keys"))
+}
+
+impl KeybindTable {
+    /// Applies keybindings on top of whatever's already bound, one `KEY: Command` per line.
+    /// Binding a key to `Nothing` unbinds it. Returns what was wrong with any lines that
+    /// didn't make sense, which get skipped.
+    pub fn apply(&mut self, text: &str) -> Vec<String> {
+        let mut errors = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.apply_line(line) {
+                errors.push(format!("line {}: {}", i + 1, e));
+            }
+        }
+        errors
+    }
+
+    fn apply_line(&mut self, line: &str) -> Result<(), String> {
+        let split = line.find(": ").ok_or_else(|| format!("Bad keybind specifier: {}", line))?;
+        let (key_spec, command) = (&line[..split], line[split + 1..].trim());
+        let key = decode_key_spec(key_spec)?;
+        if command == "Nothing" {
+            self.table.remove(&key);
+        } else {
+            let command = command.parse::<Command>().map_err(|_| format!("Bad command: {}", command))?;
+            self.table.insert(key, command);
+        }
+        Ok(())
+    }
+
+    /// The default keybindings with the user's own on top, along with anything that
+    /// was wrong with the user's
+    pub fn load() -> (KeybindTable, Vec<String>) {
+        let mut result = KeybindTable::default();
+        let path = match user_keys_path() {
+            Some(p) => p,
+            None => return (result, vec![])
+        };
+        let errors = match fs::read_to_string(&path) {
+            Ok(text) => result.apply(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => vec![e.to_string()]
+        };
+        let errors = errors.into_iter().map(|e| format!("{}: {}", path.display(), e)).collect();
+        (result, errors)
     }
-    Some(Key::Ctrl(Box::new(Key::Char(key))))
 }
 
 impl<'a> From<&'a str> for KeybindTable {
@@ -86,24 +279,7 @@ impl<'a> From<&'a str> for KeybindTable {
         let mut result = KeybindTable {
             table: HashMap::new()
         };
-        for line in text.lines() {
-            let data: Vec<_> = line.split(": ").collect();
-            if data.len() != 2 {
-                println!("Bad keybind specifier: {}", line);
-                continue
-            }
-            if let Ok(command) = data[1].parse::<Command>() {
-                let key_spec = data[0].to_lowercase();
-                match decode_key_spec(&key_spec) {
-                    Some(key) => {
-                        result.table.insert(key, command);
-                    },
-                    _ => {}
-                }
-            } else {
-                println!("Bad command: {}", data[1]);
-            }
-        }
+        result.apply(text);
         result
     }
 }
@@ -137,4 +313,40 @@ mod tests {
             _ => panic!("Looking up ^Q succeeded!")
         }
     }
+
+    #[test]
+    fn user_keybinds_layer_over_the_defaults() {
+        let mut keys = KeybindTable::default();
+        let errors = keys.apply("# mine\n^Q: Nothing\n^B: Quit\n\n^Q Quit\n^^^: Quit\n^N: Explode\n");
+        assert_eq!(errors, [
+            "line 5: Bad keybind specifier: ^Q Quit",
+            "line 6: Bad key specifier: ^^^",
+            "line 7: Bad command: Explode",
+        ]);
+        assert!(keys.lookup(ctrl('q')).is_none());
+        match keys.lookup(ctrl('b')) {
+            Some(Command::Quit) => (),
+            _ => panic!("Looking up ^B failed!")
+        }
+        match keys.lookup(ctrl('s')) {
+            Some(Command::SaveFile) => (),
+            _ => panic!("Looking up ^S failed!")
+        }
+    }
+
+    #[test]
+    fn key_specs_cover_every_key() {
+        let specs = ["^Q", "M-x", "Alt-x", "S-Tab", "^M-S-PgDn", "S-^F5", "F12", "Home", "del", "^Space", "M-Enter", "^^"];
+        let keys: Vec<Key> = specs.iter().map(|s| decode_key_spec(s).unwrap()).collect();
+        assert_eq!(keys[0], ctrl('q'));
+        assert_eq!(keys[1], Key::Alt(Box::new(Key::Char('x'))));
+        assert_eq!(keys[3], Key::Shift(Box::new(Key::Char('\t'))));
+        assert_eq!(keys[4], Key::Ctrl(Box::new(Key::Alt(Box::new(Key::Shift(Box::new(Key::PageDown)))))));
+        assert_eq!(keys[5], Key::Ctrl(Box::new(Key::Shift(Box::new(Key::F(5))))));
+        let rendered: Vec<String> = keys.iter().map(encode_key_spec).collect();
+        assert_eq!(rendered, ["^Q", "M-x", "M-x", "S-Tab", "^M-S-PgDn", "^S-F5", "F12", "Home", "Del", "^Space", "M-Enter", "^^"]);
+        for bad in &["^^^", "M-", "F0", "F25", "Hyper-x", "xy"] {
+            assert!(decode_key_spec(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
 }
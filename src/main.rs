@@ -3,6 +3,10 @@
 
 use std::io::Write;
 use std::env;
+use std::panic;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
 mod keybinds;
 use keybinds::*;
@@ -20,7 +24,25 @@ mod config;
 
 mod indent;
 
-fn get_file_size(term: &Terminal, state: &EditorState) -> (i32, i32) {
+mod history;
+
+mod search;
+
+mod grapheme;
+
+mod encoding;
+
+mod atomic;
+
+mod recovery;
+
+mod screen;
+use screen::Screen;
+
+mod session;
+use session::Recorder;
+
+fn get_file_size<T: TermImpl>(term: &T, state: &EditorState) -> (i32, i32) {
     let (screen_w, screen_h) = term.get_size();
     let one_liner_offset = match state.one_liner_active() {
         true => 1,
@@ -29,7 +51,7 @@ fn get_file_size(term: &Terminal, state: &EditorState) -> (i32, i32) {
     (screen_w - state.lineno_chars() - 1, screen_h - one_liner_offset - 4)
 }
 
-fn render_file(out: &mut Terminal, state: &EditorState) {
+fn render_file(out: &mut Screen, state: &EditorState) {
     let file_size = get_file_size(out, state);
     let mut x = 1;
     let mut y = 1;
@@ -55,7 +77,7 @@ fn render_file(out: &mut Terminal, state: &EditorState) {
     }
 }
 
-fn render_footer(out: &mut Terminal, state: &EditorState) {
+fn render_footer(out: &mut Screen, state: &EditorState) {
     let (screen_width, screen_height) = out.get_size();
 
     let mut x = 1;
@@ -76,16 +98,25 @@ fn render_footer(out: &mut Terminal, state: &EditorState) {
     }
 }
 
-fn render_status(out: &mut Terminal, state: &EditorState) {
-    let (_, height) = out.get_size();
+fn render_status(out: &mut Screen, state: &EditorState) {
+    let (width, height) = out.get_size();
     let file_size = get_file_size(out, state);
     let x = 1;
     let y = height;
     out.goto((x, y));
-    write!(out, "{}", state.debug(file_size)).unwrap();
+    let mut status = match state.message() {
+        Some(m) => m.clone(),
+        None => state.debug(file_size)
+    };
+    if state.recording_macro() {
+        status = format!("[Recording] {}", status);
+    }
+    // Pad out to the edge so a shorter status doesn't leave junk behind, but stop short of the last column
+    let status: String = status.chars().take(width as usize - 1).collect();
+    write!(out, "{:1$}", status, width as usize - 1).unwrap();
 }
 
-fn render_one_liner(out: &mut Terminal, state: &EditorState) {
+fn render_one_liner(out: &mut Screen, state: &EditorState) {
     if let &Some(ref ols) = state.one_liner() {
         let (_, screen_height) = out.get_size();
         let y = screen_height - 4;
@@ -97,10 +128,15 @@ fn render_one_liner(out: &mut Terminal, state: &EditorState) {
         out.set_color_fg(Color::Reset);
         out.set_color_bg(Color::Reset);
         write!(out, "{}", ols.value()).unwrap();
+        if let Some(ref e) = ols.error {
+            out.set_color_fg(Color::Grey);
+            write!(out, "  ({})", e).unwrap();
+            out.set_color_fg(Color::Reset);
+        }
     }
 }
 
-fn render_tab_bar(out: &mut Terminal, state: &EditorState) {
+fn render_tab_bar(out: &mut Screen, state: &EditorState) {
     let (screen_width, screen_height) = out.get_size();
     let mut x = 1;
     let y = screen_height - 3;
@@ -108,7 +144,7 @@ fn render_tab_bar(out: &mut Terminal, state: &EditorState) {
 
     for (i, f) in state.files.iter().enumerate() {
         let l = f.label();
-        let gap = tab_width as usize - l.len();
+        let gap = (tab_width as usize).saturating_sub(grapheme::display_width(&l));
         out.goto((x, y));
         out.set_color_fg(Color::Black);
         if i == state.active_file {
@@ -125,220 +161,685 @@ fn render_tab_bar(out: &mut Terminal, state: &EditorState) {
     out.set_color_bg(Color::Reset);
 }
 
-fn main() {
-    let mut term = Terminal::default();
-    term.clear();
-    term.flush().unwrap();
-    let mut state = EditorState {
-        keys: KeybindTable::default(),
-        files: vec![],
-        one_liners: vec![],
-        active_file: 0,
-    };
-    for filename in env::args().skip(1) {
-        state.files.push(File::open(&filename));
-        state.one_liners.push(None);
+/// Gets the terminal back to normal before a panic gets reported, since otherwise the message
+/// goes to the alternate screen and vanishes along with it
+fn install_panic_hook() {
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        terminal::restore();
+        report(info);
+    }));
+}
+
+/// What the command line asked for
+#[derive(Default)]
+struct Options {
+    files: Vec<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    headless: bool,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => options.record = Some(args.next().ok_or("--record needs a file to write to")?),
+            "--replay" => options.replay = Some(args.next().ok_or("--replay needs a file to read from")?),
+            "--headless" => options.headless = true,
+            _ => options.files.push(arg)
+        }
+    }
+    if options.headless && options.replay.is_none() {
+        return Err(String::from("--headless only works with --replay"));
+    }
+    Ok(options)
+}
+
+fn open_files(state: &mut EditorState, names: &[String]) {
+    for filename in names {
+        match File::open(filename) {
+            Ok(f) => {
+                state.files.push(f);
+                state.one_liners.push(None);
+            },
+            Err(e) => state.message = Some(format!("Could not open {}: {}", filename, e))
+        }
     }
     if state.files.len() == 0 {
         state.files.push(File::empty());
         state.one_liners.push(None);
     }
-    render_footer(&mut term, &state);
-    render_file(&mut term, &state);
-    render_status(&mut term, &state);
-    render_tab_bar(&mut term, &state);
-    let file_size = get_file_size(&term, &state);
-    term.goto(state.cursor(file_size));
-    term.flush().unwrap();
-    let mut screen_dirty = false;
-    for mut evt in term.keys() {
-        let file_size = get_file_size(&term, &state);
-        evt = match evt {
-            Event::Key(Key::Shift(ref k)) if k.is_navigation() => {
-                state.select();
-                Event::Key((**k).clone())
-            },
-            _ => evt
-        };
-        match evt {
-            Event::Mouse(MouseEvent::Press(MouseButton::Left, x, y)) => {
-                let left_gutter = state.lineno_chars() + 1;
-                let bottom_gutter = file_size.1 + 1;
-                if x > left_gutter && y < bottom_gutter {
-                    state.move_cursor_to(file_size, (x, y));
-                } else if y < bottom_gutter {
-                    let x = state.cursor(file_size).0;
-                    state.move_cursor_to(file_size, (x, y));
-                } else if y == bottom_gutter && !state.one_liner_active() {
-                    let (screen_height, _) = term.get_size();
-                    let tab_width = screen_height / state.files.len() as i32;
-                    state.active_file = ((x - 1) / tab_width) as usize;
-                    screen_dirty = true;
+    for i in 0..state.files.len() {
+        state.active_file = i;
+        state.check_opened();
+    }
+    state.active_file = 0;
+}
+
+/// Prints out what every file ended up looking like, so replays can be compared
+fn dump_buffers(state: &EditorState) {
+    for f in &state.files {
+        println!("==> {} <==", f.label());
+        for line in &f.lines {
+            println!("{}", line);
+        }
+    }
+}
+
+fn main() {
+    install_panic_hook();
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let recording = options.replay.as_ref().map(|path| session::load(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", path, e);
+        process::exit(1);
+    }));
+    // Replays use the keys they were recorded with, not whatever this machine has
+    let (keys, key_errors) = match recording {
+        Some(ref r) => (r.keys.clone(), vec![]),
+        None => KeybindTable::load()
+    };
+    let mut recorder = options.record.as_ref().map(|path| Recorder::create(path, &keys).unwrap_or_else(|e| {
+        eprintln!("Could not record to {}: {}", path, e);
+        process::exit(1);
+    }));
+    let mut state = EditorState::new(keys);
+    open_files(&mut state, &options.files);
+    if !key_errors.is_empty() {
+        let problems = key_errors.join("; ");
+        state.message = Some(match state.message.take() {
+            Some(m) => format!("{}; {}", m, problems),
+            None => problems
+        });
+    }
+
+    if let Some(recording) = recording {
+        if options.headless {
+            let mut term = Headless::default();
+            for (size, events) in recording.segments {
+                term.resize(size);
+                for e in events {
+                    term.send(e);
                 }
-            },
-            Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, _, _)) => {
-                state.scroll_up(file_size);
-            },
-            Event::Mouse(MouseEvent::Press(MouseButton::WheelDown, _, _)) => {
-                state.scroll_down(file_size);
-            },
-            Event::Mouse(_) => (),
-            Event::Unsupported(_) => (),
-            Event::Key(Key::Null) => (),
-            Event::Key(Key::Insert) => (),
-            Event::Key(Key::F(_)) => (),
-            Event::Key(Key::Esc) => {
-                if state.one_liner_active() {
-                    state.one_liner_mut().take();
-                } else {
-                    state.deselect();
+                let keys = term.keys();
+                run_keeping_changes(&mut term, keys, &mut state, recorder.as_mut());
+                if state.finished {
+                    break;
                 }
-                screen_dirty = true;
-            },
-            Event::Key(Key::Ctrl(ref k)) if **k == Key::Char('\t') => {
-                state.next_tab();
-                screen_dirty = true;
             }
-            Event::Key(k @ Key::Ctrl(_)) | Event::Key(k @ Key::Alt(_)) => {
-                match state.keys.lookup(k) {
-                    Some(Command::Quit) => break,
-                    Some(Command::Refresh) => {
-                        state.refresh(file_size);
-                        screen_dirty = true;
-                    },
-                    Some(Command::NewTab) => {
-                        state.new_tab();
-                        screen_dirty = true;
-                    },
-                    Some(Command::SaveFile) => {
-                        let mut ols = OneLinerState::from(Command::SaveFile);
-                        ols.file.lines[0] = state.active_file().name.clone();
-                        ols.file.move_cursor_end(file_size);
-                        state.set_one_liner(ols);
-                        screen_dirty = true;
-                    },
-                    Some(Command::OpenFile) => {
-                        let ols = OneLinerState::from(Command::OpenFile);
-                        state.set_one_liner(ols);
-                        screen_dirty = true;
-                    },
-                    Some(Command::CloseFile) => {
-                        state.close_tab();
-                        if state.files.len() == 0 {
-                            break;
+        } else {
+            // Slow enough to watch, but the sizes it was recorded at can't be helped
+            let events = recording.segments.into_iter().flat_map(|(_, events)| events)
+                .inspect(|_| thread::sleep(Duration::from_millis(50)));
+            let mut term = Terminal::default();
+            run_keeping_changes(&mut term, events, &mut state, recorder.as_mut());
+        }
+        dump_buffers(&state);
+        return;
+    }
+
+    let mut term = Terminal::default();
+    let keys = term.keys();
+    run_keeping_changes(&mut term, keys, &mut state, recorder.as_mut());
+}
+
+/// Runs the editor, and if anything panics, copies every unsaved buffer somewhere safe
+/// before letting the panic carry on
+fn run_keeping_changes<T, I>(term: &mut T, events: I, state: &mut EditorState, record: Option<&mut Recorder>)
+    where T: TermImpl, I: IntoIterator<Item = Event> {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| run(term, events, state, record)));
+    if let Err(e) = result {
+        let kept = state.dump_recovery();
+        terminal::restore();
+        for path in kept {
+            eprintln!("Unsaved changes were kept in {}", path);
+        }
+        panic::resume_unwind(e);
+    }
+}
+
+/// Draws whatever needs it into the screen buffer, then sends the terminal just the difference
+fn draw<T: TermImpl>(term: &mut T, screen: &mut Screen, state: &mut EditorState, everything: bool) {
+    let file_size = get_file_size(term, state);
+    if screen.resize(term.get_size()) || everything || state.display_dirty() {
+        screen.clear();
+        render_footer(screen, state);
+        render_tab_bar(screen, state);
+        render_file(screen, state);
+        render_one_liner(screen, state);
+        state.clean_display();
+    }
+    render_status(screen, state);
+    if state.repaint {
+        screen.invalidate();
+        state.repaint = false;
+    }
+    screen.present(term, state.cursor(file_size));
+    term.flush().unwrap();
+}
+
+/// Plays the macro back as many times as asked, stopping early if a search in it comes up empty
+fn play_macro<T: TermImpl>(term: &T, state: &mut EditorState, count: &str) -> Result<(), String> {
+    let (events, times) = state.macro_playback(count)?;
+    let mut result = Ok(());
+    'playback: for _ in 0..times {
+        let before = state.playback_position();
+        for evt in events.iter().cloned() {
+            state.continue_playback();
+            handle(term, state, evt);
+            state.update_search(get_file_size(term, state));
+            if state.search_failed() {
+                state.cancel_one_liner();
+                result = Err(String::from("Macro stopped: nothing left to find"));
+                break 'playback;
+            }
+            if state.finished {
+                break 'playback;
+            }
+        }
+        // Going around again would just do nothing again
+        if state.playback_position() == before {
+            break;
+        }
+    }
+    state.end_playback();
+    result
+}
+
+/// Does whatever an event calls for, returning whether the whole screen needs redrawing
+fn handle<T: TermImpl>(term: &T, state: &mut EditorState, mut evt: Event) -> bool {
+    let file_size = get_file_size(term, state);
+    let mut screen_dirty = false;
+    let bound = match evt {
+        Event::Key(ref k) => state.keys.lookup(k.clone()),
+        _ => None
+    };
+    evt = match evt {
+        Event::Key(Key::Shift(ref k)) if k.is_navigation() && bound.is_none() => {
+            state.select();
+            Event::Key((**k).clone())
+        },
+        _ => evt
+    };
+    match evt {
+        Event::Key(_) if bound.is_some() => {
+            match bound {
+                Some(Command::Quit) => {
+                    state.quit();
+                    screen_dirty = true;
+                },
+                Some(Command::Refresh) => {
+                    state.refresh(file_size);
+                    screen_dirty = true;
+                },
+                Some(Command::Reload) => {
+                    if let Err(e) = state.reload(file_size) {
+                        state.message = Some(e);
+                    }
+                    screen_dirty = true;
+                },
+                Some(Command::NewTab) => {
+                    state.new_tab();
+                    screen_dirty = true;
+                },
+                Some(Command::SaveFile) => {
+                    if let Err(e) = state.save_file() {
+                        state.message = Some(e);
+                    }
+                    screen_dirty = true;
+                },
+                Some(Command::SaveAs) => {
+                    let name = if state.active_file().has_path() {
+                        state.active_file().name.clone()
+                    } else {
+                        String::from("")
+                    };
+                    state.ask_save_as(&name);
+                    screen_dirty = true;
+                },
+                Some(Command::OpenFile) => {
+                    let ols = OneLinerState::from(Command::OpenFile);
+                    state.set_one_liner(ols);
+                    screen_dirty = true;
+                },
+                Some(Command::CloseFile) => {
+                    state.close_file();
+                    screen_dirty = true;
+                },
+                Some(Command::Cut) => {
+                    if let Err(e) = state.cut(file_size) {
+                        state.message = Some(e);
+                    }
+                },
+                Some(Command::Copy) => {
+                    if let Err(e) = state.copy(file_size) {
+                        state.message = Some(e);
+                    }
+                },
+                Some(Command::Paste) => {
+                    if let Err(e) = state.paste(file_size) {
+                        state.message = Some(e);
+                    }
+                },
+                Some(Command::Undo) => {
+                    state.undo(file_size);
+                },
+                Some(Command::Redo) => {
+                    state.redo(file_size);
+                },
+                Some(Command::LineEndings) => {
+                    state.convert_line_endings(file_size);
+                },
+                Some(Command::RecordMacro) => {
+                    state.toggle_macro_recording();
+                },
+                Some(Command::PlayMacro) => {
+                    if let Err(e) = state.ask_macro_count() {
+                        state.message = Some(e);
+                    }
+                    screen_dirty = true;
+                },
+                Some(Command::ToggleRegex) => {
+                    if let &mut Some(ref mut ols) = state.one_liner_mut() {
+                        match (&ols.command, &ols.stage) {
+                            (&Command::Find, _) | (&Command::FindReplace, &Stage::Input) => ols.toggle_regex(),
+                            _ => ()
                         }
-                        screen_dirty = true;
-                    },
-                    Some(Command::Cut) => {
-                        state.cut(file_size);
-                    },
-                    Some(Command::Copy) => {
-                        state.copy(file_size);
-                    },
-                    Some(Command::Paste) => {
-                        state.paste(file_size);
-                    },
-                    Some(Command::Goto) => {
-                        let ols = OneLinerState::from(Command::Goto);
-                        state.set_one_liner(ols);
-                        screen_dirty = true;
-                    },
-                    None => (),
-                    Some(c) => {
-                        let mut ols = OneLinerState::from(c);
-                        ols.label = "Nope.";
-                        ols.file.lines[0] = String::from("That doesn't work yet. Press Esc to move on");
-                        ols.file.move_cursor_end(file_size);
+                    }
+                    screen_dirty = true;
+                },
+                Some(Command::Goto) => {
+                    let ols = OneLinerState::from(Command::Goto);
+                    state.set_one_liner(ols);
+                    screen_dirty = true;
+                },
+                Some(Command::FindReplace) => {
+                    if state.one_liner_active() {
+                        state.cancel_one_liner();
+                    }
+                    state.begin_search();
+                    let ols = OneLinerState::from(Command::FindReplace);
+                    state.set_one_liner(ols);
+                    screen_dirty = true;
+                },
+                Some(Command::Find) => {
+                    if let Some(Command::Find) = state.one_liner_command() {
+                        state.find_next(file_size);
+                    } else {
+                        state.begin_search();
+                        let ols = OneLinerState::from(Command::Find);
                         state.set_one_liner(ols);
-                        screen_dirty = true;
                     }
-                }
-            },
-            Event::Key(Key::Left) => {
-                state.move_cursor_left(file_size);
-            },
-            Event::Key(Key::Right) => {
-                state.move_cursor_right(file_size);
-            },
-            Event::Key(Key::Up) => {
-                state.move_cursor_up(file_size);
-            },
-            Event::Key(Key::Down) => {
-                state.move_cursor_down(file_size);
-            },
-            Event::Key(Key::Home) => state.move_cursor_home(file_size),
-            Event::Key(Key::End) => state.move_cursor_end(file_size),
-            Event::Key(Key::PageUp) => {
-                state.page_up(file_size);
-                screen_dirty = true;
-            },
-            Event::Key(Key::PageDown) => {
-                state.page_down(file_size);
+                    screen_dirty = true;
+                },
+                None => (),
+            }
+        },
+        Event::Mouse(MouseEvent::Press(MouseButton::Left, x, y)) => {
+            let left_gutter = state.lineno_chars() + 1;
+            let bottom_gutter = file_size.1 + 1;
+            if x > left_gutter && y < bottom_gutter {
+                state.move_cursor_to(file_size, (x, y));
+            } else if y < bottom_gutter {
+                let x = state.cursor(file_size).0;
+                state.move_cursor_to(file_size, (x, y));
+            } else if y == bottom_gutter && !state.one_liner_active() {
+                let (screen_height, _) = term.get_size();
+                let tab_width = screen_height / state.files.len() as i32;
+                state.switch_tab(((x - 1) / tab_width) as usize);
                 screen_dirty = true;
-            },
-            Event::Key(Key::Char('\t')) if state.one_liner_active() => {
-                if let &mut Some(ref mut ols) = state.one_liner_mut() {
-                    match ols.command {
-                        Command::SaveFile | Command::OpenFile => ols.tab(),
-                        _ => ()
-                    }
+            }
+        },
+        Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, _, _)) => {
+            state.scroll_up(file_size);
+        },
+        Event::Mouse(MouseEvent::Press(MouseButton::WheelDown, _, _)) => {
+            state.scroll_down(file_size);
+        },
+        Event::Mouse(_) => (),
+        Event::Idle => (),
+        Event::Resize => {
+            state.refresh(file_size);
+            screen_dirty = true;
+        },
+        Event::Unsupported(_) => (),
+        Event::Key(Key::Null) => (),
+        Event::Key(Key::Insert) => (),
+        Event::Key(Key::F(_)) => (),
+        Event::Key(Key::Esc) => {
+            if state.one_liner_active() {
+                state.cancel_one_liner();
+            } else {
+                state.deselect();
+            }
+            screen_dirty = true;
+        },
+        Event::Key(Key::Ctrl(ref k)) if **k == Key::Char('\t') => {
+            state.next_tab();
+            screen_dirty = true;
+        }
+        Event::Key(Key::Ctrl(_)) | Event::Key(Key::Alt(_)) => (),
+        Event::Key(Key::Left) => {
+            state.move_cursor_left(file_size);
+        },
+        Event::Key(Key::Right) => {
+            state.move_cursor_right(file_size);
+        },
+        Event::Key(Key::Up) => {
+            state.move_cursor_up(file_size);
+        },
+        Event::Key(Key::Down) => {
+            state.move_cursor_down(file_size);
+        },
+        Event::Key(Key::Home) => state.move_cursor_home(file_size),
+        Event::Key(Key::End) => state.move_cursor_end(file_size),
+        Event::Key(Key::PageUp) => {
+            state.page_up(file_size);
+            screen_dirty = true;
+        },
+        Event::Key(Key::PageDown) => {
+            state.page_down(file_size);
+            screen_dirty = true;
+        },
+        Event::Key(Key::Char('\t')) if state.one_liner_active() => {
+            let mut result = Ok(());
+            let mut literal = false;
+            if let &mut Some(ref mut ols) = state.one_liner_mut() {
+                match (&ols.command, &ols.stage) {
+                    (&Command::SaveAs, &Stage::Input) | (&Command::OpenFile, &Stage::Input) => result = ols.tab(),
+                    (&Command::Find, _) | (&Command::FindReplace, &Stage::Input) | (&Command::FindReplace, &Stage::ReplaceWith) => literal = true,
+                    _ => ()
                 }
-            },
-            Event::Key(Key::Char('\t')) => {
-                state.tab(file_size)
-            },
-            Event::Key(Key::Char('\n')) => {
-                if let Some((command, value)) = state.consume_one_liner() {
-                    match command {
-                        Command::SaveFile => {
-                            state.save_file(&value);
-                        },
-                        Command::OpenFile => {
-                            state.open_file(&value);
-                        },
-                        Command::Goto => {
-                            state.goto(file_size, &value);
-                        },
-                        _ => ()
-                    };
-                } else {
-                    state.insert_newline(file_size, true);
+            }
+            // Searching for a tab means typing one
+            if literal {
+                state.insert(file_size, '\t');
+            }
+            if let Err(e) = result {
+                state.message = Some(e);
+            }
+        },
+        Event::Key(Key::Char('\t')) => {
+            state.tab(file_size)
+        },
+        Event::Key(Key::Char('\n')) => {
+            if state.one_liner_is_choice() {
+                state.choose(file_size, '\n');
+            } else if let Some(Command::Find) = state.one_liner_command() {
+                state.find_next(file_size);
+            } else if let Some(Command::FindReplace) = state.one_liner_command() {
+                state.advance_replace();
+            } else if let Some((command, value)) = state.consume_one_liner() {
+                let result = match command {
+                    Command::SaveAs => state.save_as(&value),
+                    Command::OpenFile => state.open_file(&value),
+                    Command::Goto => state.goto(file_size, &value),
+                    Command::PlayMacro => play_macro(term, state, &value),
+                    _ => Ok(())
+                };
+                if let Err(e) = result {
+                    state.message = Some(e);
                 }
+            } else {
+                state.insert_newline(file_size, true);
+            }
+            screen_dirty = true;
+        },
+        Event::Key(Key::Delete) => {
+            state.delete(file_size);
+            screen_dirty = true;
+        },
+        Event::Key(Key::Backspace) => {
+            state.backspace(file_size);
+            screen_dirty = true;
+        },
+        Event::Key(Key::Char(c)) if state.one_liner_is_choice() => {
+            state.choose(file_size, c);
+            screen_dirty = true;
+        },
+        Event::Key(Key::Shift(ref k)) if k.is_char() && state.one_liner_is_choice() => {
+            if let Key::Char(c) = **k {
+                state.choose(file_size, c);
                 screen_dirty = true;
-            },
-            Event::Key(Key::Delete) => {
-                state.delete(file_size);
-                screen_dirty = true;
-            },
-            Event::Key(Key::Backspace) => {
-                state.backspace(file_size);
-                screen_dirty = true;
-            },
-            Event::Key(Key::Shift(ref k)) if k.is_char() => {
-                match **k {
-                    Key::Char(c) => {
-                        state.insert(file_size, c);
-                    },
-                    _ => panic!("This was just the right thing!")
-                }
-            },
-            Event::Key(Key::Char(c)) => {
-                state.insert(file_size, c);
-            },
-            Event::Key(Key::Shift(_)) => ()
+            }
+        },
+        Event::Key(Key::Shift(ref k)) if k.is_char() => {
+            match **k {
+                Key::Char(c) => {
+                    state.insert(file_size, c);
+                },
+                _ => panic!("This was just the right thing!")
+            }
+        },
+        Event::Key(Key::Char(c)) => {
+            state.insert(file_size, c);
+        },
+        Event::Key(Key::Shift(_)) => ()
+    }
+    screen_dirty
+}
+
+/// Handles events and draws the results until it's time to quit or the events run out
+fn run<T, I>(term: &mut T, events: I, state: &mut EditorState, mut record: Option<&mut Recorder>)
+    where T: TermImpl, I: IntoIterator<Item = Event> {
+    let mut screen = Screen::default();
+    draw(term, &mut screen, state, true);
+    let mut screen_dirty = false;
+    for evt in events {
+        if let Event::Idle = evt {
+            // Quiet spells are when the last few edits make it into the recovery copies
+            state.write_recovery();
+            draw(term, &mut screen, state, false);
+            continue;
+        }
+        state.clear_message();
+        let failed = match record {
+            Some(ref mut r) => r.log(term.get_size(), &evt).err(),
+            None => None
+        };
+        if let Some(e) = failed {
+            state.message = Some(format!("Stopped recording: {}", e));
+            record = None;
         }
-        let file_size = get_file_size(&term, &state);
-        if state.display_dirty() || screen_dirty {
-            term.clear();
-            render_footer(&mut term, &state);
-            render_tab_bar(&mut term, &state);
-            render_file(&mut term, &state);
-            render_one_liner(&mut term, &state);
-            screen_dirty = false;
-            state.clean_display();
+        state.capture(&evt);
+        screen_dirty |= handle(term, state, evt);
+        if state.finished {
+            state.forget_recovery();
+            break;
         }
-        render_status(&mut term, &state);
-        term.goto(state.cursor(file_size));
-        term.flush().unwrap();
+        state.write_recovery();
+        let file_size = get_file_size(term, state);
+        state.update_search(file_size);
+        draw(term, &mut screen, state, screen_dirty);
+        screen_dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor() -> EditorState {
+        let mut state = EditorState::new(KeybindTable::default());
+        state.files.push(File::empty());
+        state.one_liners.push(None);
+        state
+    }
+
+    fn play(term: &mut Headless, state: &mut EditorState) {
+        let keys = term.keys();
+        run(term, keys, state, None);
+    }
+
+    fn ctrl(c: char) -> Event {
+        Event::Key(Key::Ctrl(Box::new(Key::Char(c))))
+    }
+
+    #[test]
+    fn typing_shows_up_on_screen() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        term.type_text("hi\nthere");
+        play(&mut term, &mut state);
+        assert_eq!(term.row(1), "1 hi");
+        assert_eq!(term.row(2), "2 there");
+        assert_eq!(term.cursor(), (8, 2));
+        assert_eq!(term.row(9).trim(), "*<empty>");
+        assert!(term.row(12).starts_with("Caret (6, 2"));
+    }
+
+    #[test]
+    fn refresh_repaints_a_garbled_screen() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        let mut screen = Screen::default();
+        state.files[0].lines = vec![String::from("hi")];
+        draw(&mut term, &mut screen, &mut state, true);
+        term.goto((1, 1));
+        write!(term, "garbage").unwrap();
+        let everything = handle(&term, &mut state, ctrl('u'));
+        draw(&mut term, &mut screen, &mut state, everything);
+        assert_eq!(term.row(1), "1 hi");
+    }
+
+    #[test]
+    fn tabs_footer_and_one_liner_are_drawn() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        term.send(ctrl('t'));
+        term.send(ctrl('g'));
+        play(&mut term, &mut state);
+        let footer = format!("{}{}", term.row(10), term.row(11));
+        assert!(footer.contains("^Q Quit"));
+        assert!(footer.contains("^S SaveFile"));
+        assert_eq!(term.row(8), "Jump to line[:col]:");
+        assert_eq!(term.cursor(), (21, 8));
+        assert_eq!(term.colors_at((40, 9)), (Color::Black, Color::Grey));
+        assert_eq!(term.colors_at((120, 9)), (Color::Black, Color::White));
+
+        term.type_text("oops\n");
+        play(&mut term, &mut state);
+        assert_eq!(term.row(8), "");
+        assert!(term.row(12).starts_with("Not a line[:col]: oops"));
+    }
+
+    #[test]
+    fn function_and_shifted_keys_can_be_bound() {
+        let mut term = Headless::new((240, 12));
+        let mut state = editor();
+        assert!(state.keys.apply("F2: Goto\nS-End: Undo").is_empty());
+        state.files[0].lines = vec![String::from("abc")];
+        term.send(Event::Key(Key::F(2)));
+        play(&mut term, &mut state);
+        let footer = format!("{}{}", term.row(10), term.row(11));
+        assert!(footer.contains("F2 Goto"));
+        assert!(footer.contains("S-End Undo"));
+        assert_eq!(term.row(8), "Jump to line[:col]:");
+
+        term.send(Event::Key(Key::Esc));
+        term.send(Event::Key(Key::Char('x')));
+        term.send(Event::Key(Key::Shift(Box::new(Key::End))));
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].lines, ["abc"]);
+    }
+
+    #[test]
+    fn tabs_can_be_searched_for() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        state.files[0].lines = vec![String::from("a b\tc")];
+        term.send(ctrl('f'));
+        term.type_text("\t");
+        term.send(Event::Key(Key::Alt(Box::new(Key::Char('r')))));
+        term.send(Event::Key(Key::Esc));
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].caret.x, 4);
+        assert!(state.one_liner().is_none());
+    }
+
+    #[test]
+    fn macros_repeat_until_a_search_fails() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        state.files[0].lines = ["x1", "x2", "x3", "y"].iter().map(|l| l.to_string()).collect();
+        term.send(ctrl('k'));
+        term.send(ctrl('f'));
+        term.type_text("x");
+        term.send(Event::Key(Key::Esc));
+        term.send(Event::Key(Key::Delete));
+        term.send(ctrl('k'));
+        play(&mut term, &mut state);
+        assert!(term.row(12).starts_with("Recorded a macro of 4 keys"));
+        assert_eq!(state.files[0].lines, ["1", "x2", "x3", "y"]);
+
+        term.send(ctrl('p'));
+        term.type_text("10\n");
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].lines, ["1", "2", "3", "y"]);
+        assert!(term.row(12).starts_with("Macro stopped"));
+        assert!(!state.one_liner_active());
+
+        term.send(ctrl('z'));
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].lines, ["1", "x2", "x3", "y"]);
+    }
+
+    #[test]
+    fn macros_leave_other_tabs_redo_alone() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        state.new_tab();
+        term.type_text("a");
+        term.send(ctrl('z'));
+        term.send(Event::Key(Key::Ctrl(Box::new(Key::Char('\t')))));
+        term.send(ctrl('k'));
+        term.type_text("x");
+        term.send(ctrl('k'));
+        term.send(ctrl('p'));
+        term.type_text("2\n");
+        term.send(Event::Key(Key::Ctrl(Box::new(Key::Char('\t')))));
+        term.send(ctrl('y'));
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].lines, ["xxx"]);
+        assert_eq!(state.files[1].lines, ["a"]);
+    }
+
+    #[test]
+    fn macros_stop_once_they_stop_doing_anything() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        state.files[0].lines = vec![String::from("abc")];
+        term.send(ctrl('k'));
+        term.send(Event::Key(Key::Right));
+        term.send(ctrl('k'));
+        term.send(ctrl('p'));
+        term.type_text("1000000000\n");
+        play(&mut term, &mut state);
+        assert!(term.row(12).starts_with("Can't play a macro more than"));
+        term.send(ctrl('p'));
+        term.type_text("100000\n");
+        play(&mut term, &mut state);
+        assert_eq!(state.files[0].caret.x, 4);
+    }
+
+    #[test]
+    fn macros_cant_undo() {
+        let mut term = Headless::new((160, 12));
+        let mut state = editor();
+        term.send(ctrl('k'));
+        term.type_text("ab");
+        term.send(ctrl('z'));
+        term.send(ctrl('k'));
+        term.send(ctrl('p'));
+        term.type_text("\n");
+        play(&mut term, &mut state);
+        assert!(term.row(12).starts_with("Can't play a macro that undoes"));
+        assert_eq!(state.files[0].lines, [""]);
     }
 }
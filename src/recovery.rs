@@ -0,0 +1,75 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::atomic;
+
+/// Where recovery copies live: `$XDG_STATE_HOME/mfte`, which is usually `~/.local/state/mfte`
+#[cfg(not(windows))]
+pub fn dir() -> Option<PathBuf> {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(ref d) if !d.is_empty() => Some(PathBuf::from(d)),
+        _ => env::var_os("HOME").map(|h| Path::new(&h).join(".local").join("state"))
+    }.map(|d| d.join("mfte"))
+}
+
+#[cfg(windows)]
+pub fn dir() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA").map(|d| Path::new(&d).join("mfte"))
+}
+
+/// The recovery copy for a file in `dir`, named after its whole path with anything
+/// that isn't safe in a file name written out as `%XX`
+pub fn path_for(dir: &Path, name: &str) -> Option<PathBuf> {
+    let absolute = match fs::canonicalize(name) {
+        Ok(p) => p,
+        Err(_) => env::current_dir().ok()?.join(name)
+    };
+    let mut key = String::new();
+    for b in absolute.to_string_lossy().bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => key.push(b as char),
+            _ => key.push_str(&format!("%{:02X}", b))
+        }
+    }
+    key.push_str(".swp");
+    Some(dir.join(key))
+}
+
+pub fn save(dir: &Path, name: &str, contents: &[u8]) -> io::Result<PathBuf> {
+    let path = path_for(dir, name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "can't tell where the file is"))?;
+    fs::create_dir_all(dir)?;
+    atomic::write(&path, contents)?;
+    Ok(path)
+}
+
+/// The recovery copy for a file, if there is one that's newer than the file itself
+pub fn newer_copy(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = path_for(dir, name)?;
+    let saved = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+    match fs::metadata(name).and_then(|m| m.modified()) {
+        Ok(modified) if modified > saved => None,
+        _ => Some(path)
+    }
+}
+
+pub fn remove(dir: &Path, name: &str) {
+    if let Some(path) = path_for(dir, name) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn names_are_flattened() {
+        let path = path_for(Path::new("/state"), "/tmp/a b/c.txt").unwrap();
+        assert_eq!(path.parent(), Some(Path::new("/state")));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name, "%2Ftmp%2Fa%20b%2Fc.txt.swp");
+    }
+}
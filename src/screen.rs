@@ -0,0 +1,312 @@
+use std::io::{self, Write};
+
+use super::terminal::*;
+use super::grapheme::{self, UnicodeSegmentation};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Cell {
+    /// What's drawn here. Empty means the right half of a wide character from the cell before.
+    text: String,
+    fg: Color,
+    bg: Color,
+}
+
+impl Cell {
+    fn blank() -> Cell {
+        Cell {
+            text: String::from(" "),
+            fg: Color::Reset,
+            bg: Color::Reset,
+        }
+    }
+}
+
+/// A frame's worth of cells that the `render_*` functions draw into. `present` then
+/// sends the terminal only what changed since the frame before, so small edits
+/// don't mean repainting (and flickering) the whole screen.
+pub struct Screen {
+    size: (i32, i32),
+    cells: Vec<Cell>,
+    /// What the terminal is showing right now, or `None` if nobody knows
+    shown: Option<Vec<Cell>>,
+    cursor: (i32, i32),
+    fg: Color,
+    bg: Color,
+    /// Where the terminal's cursor is and what colors it's using, if that's known
+    at: Option<(i32, i32)>,
+    colors: Option<(Color, Color)>,
+}
+
+impl Default for Screen {
+    fn default() -> Screen {
+        Screen {
+            size: (0, 0),
+            cells: vec![],
+            shown: None,
+            cursor: (1, 1),
+            fg: Color::Reset,
+            bg: Color::Reset,
+            at: None,
+            colors: None,
+        }
+    }
+}
+
+impl Screen {
+    /// Matches the buffer to the terminal's size, returning whether it had to change.
+    /// Everything has to be drawn again after that.
+    pub fn resize(&mut self, size: (i32, i32)) -> bool {
+        if size == self.size {
+            return false;
+        }
+        self.size = size;
+        self.cells = vec![Cell::blank(); (size.0.max(0) * size.1.max(0)) as usize];
+        self.shown = None;
+        true
+    }
+
+    /// Forgets what the terminal is showing, so the next `present` clears it and sends everything
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    fn index(&self, (x, y): (i32, i32)) -> Option<usize> {
+        if x < 1 || y < 1 || x > self.size.0 || y > self.size.1 {
+            None
+        } else {
+            Some(((y - 1) * self.size.0 + (x - 1)) as usize)
+        }
+    }
+
+    fn put(&mut self, g: &str) {
+        let width = grapheme::display_width(g) as i32;
+        if width == 0 {
+            // Combining marks and such belong with whatever came right before them
+            let (x, y) = self.cursor;
+            let mut before = self.index((x - 1, y));
+            if let Some(i) = before {
+                if self.cells[i].text.is_empty() {
+                    before = self.index((x - 2, y));
+                }
+            }
+            if let Some(i) = before {
+                self.cells[i].text.push_str(g);
+            }
+            return;
+        }
+        let (x, y) = self.cursor;
+        self.cursor.0 += width;
+        let i = match self.index((x, y)) {
+            Some(i) => i,
+            None => return,
+        };
+        // Don't leave half of a wide character behind when drawing over the other half
+        if self.cells[i].text.is_empty() {
+            if let Some(j) = self.index((x - 1, y)) {
+                self.cells[j].text = String::from(" ");
+            }
+        }
+        if let Some(j) = self.index((x + width, y)) {
+            if self.cells[j].text.is_empty() {
+                self.cells[j].text = String::from(" ");
+            }
+        }
+        let fits = x + width - 1 <= self.size.0;
+        self.cells[i] = Cell {
+            text: if fits { String::from(g) } else { String::from(" ") },
+            fg: self.fg,
+            bg: self.bg,
+        };
+        if fits {
+            for dx in 1..width {
+                let j = i + dx as usize;
+                self.cells[j] = Cell {
+                    text: String::new(),
+                    fg: self.fg,
+                    bg: self.bg,
+                };
+            }
+        }
+    }
+
+    /// Sends the terminal whatever changed since last time and leaves its cursor at `cursor`,
+    /// moving the cursor and switching colors only when it has to
+    pub fn present<T: TermImpl>(&mut self, out: &mut T, cursor: (i32, i32)) {
+        let mut shown = match self.shown.take() {
+            Some(shown) => shown,
+            None => {
+                out.clear();
+                self.at = None;
+                self.colors = None;
+                vec![Cell::blank(); self.cells.len()]
+            }
+        };
+        for y in 1..self.size.1 + 1 {
+            for x in 1..self.size.0 + 1 {
+                let i = ((y - 1) * self.size.0 + (x - 1)) as usize;
+                if self.cells[i] == shown[i] || self.cells[i].text.is_empty() {
+                    continue;
+                }
+                match self.at {
+                    Some((at_x, at_y)) if at_y == y && at_x <= x => {
+                        // Going over a few cells that are already right is cheaper than jumping past them
+                        let skipped = &self.cells[i - (x - at_x) as usize..i];
+                        if skipped.len() <= 4 && skipped.iter().all(|c| Some((c.fg, c.bg)) == self.colors) {
+                            for c in skipped {
+                                write!(out, "{}", c.text).unwrap();
+                            }
+                        } else {
+                            out.goto((x, y));
+                        }
+                    },
+                    _ => out.goto((x, y)),
+                }
+                let cell = &self.cells[i];
+                if self.colors != Some((cell.fg, cell.bg)) {
+                    out.set_color_fg(cell.fg);
+                    out.set_color_bg(cell.bg);
+                    self.colors = Some((cell.fg, cell.bg));
+                }
+                write!(out, "{}", cell.text).unwrap();
+                let x = x + grapheme::display_width(&cell.text) as i32;
+                // The terminal's cursor sticks on the last column instead of going past it
+                self.at = if x > self.size.0 { None } else { Some((x, y)) };
+            }
+        }
+        if self.at != Some(cursor) {
+            out.goto(cursor);
+            self.at = Some(cursor);
+        }
+        shown.clone_from(&self.cells);
+        self.shown = Some(shown);
+    }
+}
+
+impl Write for Screen {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // write! hands over whole formatted pieces, so there's no splitting characters in half to worry about
+        let text = String::from_utf8_lossy(data);
+        for g in text.graphemes(true) {
+            self.put(g);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TermImpl for Screen {
+    fn get_size(&self) -> (i32, i32) {
+        self.size
+    }
+
+    fn goto(&mut self, pos: (i32, i32)) {
+        self.cursor = pos;
+    }
+
+    fn set_color_fg(&mut self, c: Color) {
+        self.fg = c;
+    }
+
+    fn set_color_bg(&mut self, c: Color) {
+        self.bg = c;
+    }
+
+    fn clear(&mut self) {
+        for cell in &mut self.cells {
+            *cell = Cell::blank();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps track of everything a `Screen` sends out
+    #[derive(Default)]
+    struct Recorder {
+        sent: String,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.sent.push_str(&String::from_utf8_lossy(data));
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TermImpl for Recorder {
+        fn get_size(&self) -> (i32, i32) {
+            (10, 3)
+        }
+
+        fn goto(&mut self, (x, y): (i32, i32)) {
+            self.sent.push_str(&format!("[{},{}]", x, y));
+        }
+
+        fn set_color_fg(&mut self, c: Color) {
+            self.sent.push_str(&format!("[fg {:?}]", c));
+        }
+
+        fn set_color_bg(&mut self, c: Color) {
+            self.sent.push_str(&format!("[bg {:?}]", c));
+        }
+
+        fn clear(&mut self) {
+            self.sent.push_str("[clear]");
+        }
+    }
+
+    #[test]
+    fn only_changes_are_sent() {
+        let mut screen = Screen::default();
+        let mut out = Recorder::default();
+        screen.resize(out.get_size());
+        screen.goto((1, 1));
+        write!(screen, "hello world").unwrap();
+        screen.present(&mut out, (6, 1));
+        assert_eq!(out.sent, "[clear][1,1][fg Reset][bg Reset]hello worl[6,1]");
+
+        out.sent.clear();
+        screen.clear();
+        screen.goto((1, 1));
+        write!(screen, "helpo world").unwrap();
+        screen.set_color_fg(Color::Grey);
+        screen.goto((1, 3));
+        write!(screen, "!").unwrap();
+        screen.present(&mut out, (1, 3));
+        assert_eq!(out.sent, "[4,1]p[1,3][fg Grey][bg Reset]![1,3]");
+
+        out.sent.clear();
+        screen.present(&mut out, (1, 3));
+        assert_eq!(out.sent, "");
+
+        screen.invalidate();
+        screen.present(&mut out, (1, 3));
+        assert!(out.sent.starts_with("[clear][1,1][fg Reset][bg Reset]helpo worl"));
+    }
+
+    #[test]
+    fn wide_characters_take_two_cells() {
+        let mut screen = Screen::default();
+        let mut out = Recorder::default();
+        screen.resize(out.get_size());
+        screen.goto((1, 2));
+        write!(screen, "\u{65E5}\u{672C}e\u{301}").unwrap();
+        screen.present(&mut out, (6, 2));
+        assert_eq!(out.sent, "[clear][1,2][fg Reset][bg Reset]\u{65E5}\u{672C}e\u{301}");
+
+        out.sent.clear();
+        screen.goto((2, 2));
+        write!(screen, "x").unwrap();
+        screen.present(&mut out, (3, 2));
+        assert_eq!(out.sent, "[1,2] x");
+    }
+}
@@ -0,0 +1,133 @@
+extern crate regex;
+use self::regex::Regex;
+
+/// Finds occurrences of a search term, one line at a time.
+/// Regexes only ever see a single line, so `^` and `$` anchor to line boundaries.
+pub struct Matcher {
+    needle: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(needle: &str) -> Matcher {
+        Matcher {
+            needle: String::from(needle),
+            regex: None,
+        }
+    }
+
+    /// Compiles a regex, or explains (in one line) why it won't compile
+    pub fn regex(needle: &str) -> Result<Matcher, String> {
+        match Regex::new(needle) {
+            Ok(r) => Ok(Matcher {
+                needle: String::from(needle),
+                regex: Some(r),
+            }),
+            Err(e) => Err(e.to_string().lines().last().unwrap_or("bad regex").trim().to_string())
+        }
+    }
+
+    pub fn needle(&self) -> &str {
+        &self.needle
+    }
+
+    pub fn is_regex(&self) -> bool {
+        self.regex.is_some()
+    }
+
+    /// Byte ranges of every non-overlapping match in the line
+    pub fn find_all(&self, line: &str) -> Vec<(usize, usize)> {
+        if self.needle.is_empty() {
+            return vec![];
+        }
+        match self.regex {
+            Some(ref r) => r.find_iter(line).map(|m| (m.start(), m.end())).collect(),
+            None => line.match_indices(&self.needle).map(|(i, m)| (i, i + m.len())).collect()
+        }
+    }
+
+    /// The byte range of the match starting at `start` and what it should be replaced with,
+    /// with `$1` and `${name}` filled in from the regex's capture groups
+    pub fn replacement(&self, line: &str, start: usize, template: &str) -> Option<(usize, usize, String)> {
+        match self.regex {
+            Some(ref r) => r.captures_iter(line).find(|c| c.get(0).map(|m| m.start()) == Some(start)).map(|c| {
+                let mut result = String::new();
+                c.expand(template, &mut result);
+                let m = c.get(0).unwrap();
+                (m.start(), m.end(), result)
+            }),
+            None => self.find_all(line).into_iter()
+                .find(|&(s, _)| s == start)
+                .map(|(s, e)| (s, e, String::from(template)))
+        }
+    }
+}
+
+/// Keeps a confirm-each replace going until it has been once around the file
+pub struct Replacement {
+    pub text: String,
+    pub count: usize,
+    stop: (i32, i32),
+    wrapped: bool,
+}
+
+impl Replacement {
+    /// `stop` is the first match, where the trip around the file ends
+    pub fn new(text: &str, stop: (i32, i32)) -> Replacement {
+        Replacement {
+            text: String::from(text),
+            count: 0,
+            stop,
+            wrapped: false,
+        }
+    }
+
+    /// Notes a match that was replaced, since it may move where we need to stop
+    pub fn replaced(&mut self, at: (i32, i32), old_len: usize, new_len: usize) {
+        self.count += 1;
+        if at.0 == self.stop.0 && at.1 < self.stop.1 {
+            self.stop.1 += new_len as i32 - old_len as i32;
+        }
+    }
+
+    /// Whether the match at `next` still needs visiting
+    pub fn advance(&mut self, next: (i32, i32), wrapped: bool) -> bool {
+        if wrapped {
+            if self.wrapped {
+                return false;
+            }
+            self.wrapped = true;
+        }
+        !(self.wrapped && next >= self.stop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_do_not_overlap() {
+        let m = Matcher::new("aa");
+        assert_eq!(m.find_all("aaaaa"), vec![(0, 2), (2, 4)]);
+        assert_eq!(Matcher::new("").find_all("aaaaa"), vec![]);
+    }
+
+    #[test]
+    fn replacement_stops_after_one_trip() {
+        let mut r = Replacement::new("x", (2, 5));
+        assert!(r.advance((3, 1), false));
+        assert!(r.advance((1, 1), true));
+        r.replaced((2, 1), 3, 1);
+        assert!(!r.advance((2, 3), false));
+    }
+
+    #[test]
+    fn regex_anchors_and_captures() {
+        let m = Matcher::regex(r"^(?P<key>\w+)=(\w+)$").unwrap();
+        assert_eq!(m.find_all("a=b"), vec![(0, 3)]);
+        assert_eq!(m.find_all(" a=b"), vec![]);
+        assert_eq!(m.replacement("a=b", 0, "$2=${key}"), Some((0, 3, String::from("b=a"))));
+        assert!(Matcher::regex("(").is_err());
+    }
+}
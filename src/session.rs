@@ -0,0 +1,247 @@
+use std::fs;
+use std::io::{self, Write};
+
+use super::terminal::*;
+use super::keybinds::KeybindTable;
+
+/// Writes down every event as it happens, along with the terminal's size whenever that changes.
+/// Each event gets its own line, like `key ctrl U+0073` or `mouse press left 10 4`, after
+/// a `bind` line for each keybinding so the same keys do the same things on replay.
+pub struct Recorder {
+    out: fs::File,
+    size: Option<(i32, i32)>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, keys: &KeybindTable) -> io::Result<Recorder> {
+        let mut out = fs::File::create(path)?;
+        let mut binds: Vec<String> = keys.entries().into_iter()
+            .map(|(key, command)| format!("bind {}: {}\n", key, command)).collect();
+        binds.sort();
+        out.write_all(binds.concat().as_bytes())?;
+        Ok(Recorder {
+            out,
+            size: None,
+        })
+    }
+
+    /// Nothing gets buffered, so the last event before a crash still makes it into the file
+    pub fn log(&mut self, size: (i32, i32), e: &Event) -> io::Result<()> {
+        let mut text = String::new();
+        if self.size != Some(size) {
+            text.push_str(&format!("size {} {}\n", size.0, size.1));
+            self.size = Some(size);
+        }
+        text.push_str(&encode(e));
+        text.push('\n');
+        self.out.write_all(text.as_bytes())
+    }
+}
+
+/// A recorded session, split up wherever the terminal changed size
+pub struct Recording {
+    /// The keybindings it was recorded with
+    pub keys: KeybindTable,
+    pub segments: Vec<((i32, i32), Vec<Event>)>,
+}
+
+pub fn load(path: &str) -> io::Result<Recording> {
+    let text = fs::read_to_string(path)?;
+    let mut binds = String::new();
+    let mut segments = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("line {} of {}: {}", i + 1, path, line));
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words[0] == "bind" {
+            binds.push_str(&line["bind".len()..]);
+            binds.push('\n');
+        } else if words[0] == "size" {
+            match numbers(&words[1..]) {
+                Some(ref n) if n.len() == 2 => segments.push(((n[0], n[1]), vec![])),
+                _ => return Err(bad())
+            }
+        } else {
+            let e = decode(&words).ok_or_else(&bad)?;
+            match segments.last_mut() {
+                Some(&mut (_, ref mut events)) => events.push(e),
+                None => return Err(bad())
+            }
+        }
+    }
+    // Recordings from before keybindings were written down used the defaults
+    if binds.is_empty() {
+        return Ok(Recording {
+            keys: KeybindTable::default(),
+            segments,
+        });
+    }
+    let mut keys = KeybindTable::from("");
+    let errors = keys.apply(&binds);
+    if !errors.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("keybindings in {}: {}", path, errors.join("; "))));
+    }
+    Ok(Recording {
+        keys,
+        segments,
+    })
+}
+
+fn numbers(words: &[&str]) -> Option<Vec<i32>> {
+    words.iter().map(|w| w.parse().ok()).collect()
+}
+
+fn encode(e: &Event) -> String {
+    match *e {
+        Event::Key(ref k) => format!("key {}", encode_key(k)),
+        Event::Mouse(MouseEvent::Press(ref b, x, y)) => {
+            let button = match *b {
+                MouseButton::Left => "left",
+                MouseButton::Right => "right",
+                MouseButton::Middle => "middle",
+                MouseButton::WheelUp => "wheelup",
+                MouseButton::WheelDown => "wheeldown",
+            };
+            format!("mouse press {} {} {}", button, x, y)
+        },
+        Event::Mouse(MouseEvent::Release(x, y)) => format!("mouse release {} {}", x, y),
+        Event::Mouse(MouseEvent::Hold(x, y)) => format!("mouse hold {} {}", x, y),
+        Event::Resize => String::from("resize"),
+        Event::Idle => String::from("idle"),
+        Event::Unsupported(ref v) => {
+            let codes: Vec<String> = v.iter().map(|c| c.to_string()).collect();
+            format!("unsupported {}", codes.join(" ")).trim().to_string()
+        }
+    }
+}
+
+fn encode_key(k: &Key) -> String {
+    match *k {
+        Key::Ctrl(ref k) => format!("ctrl {}", encode_key(k)),
+        Key::Alt(ref k) => format!("alt {}", encode_key(k)),
+        Key::Shift(ref k) => format!("shift {}", encode_key(k)),
+        Key::Char(c) => format!("U+{:04X}", c as u32),
+        Key::F(n) => format!("f{}", n),
+        Key::Backspace => String::from("backspace"),
+        Key::Left => String::from("left"),
+        Key::Right => String::from("right"),
+        Key::Up => String::from("up"),
+        Key::Down => String::from("down"),
+        Key::Home => String::from("home"),
+        Key::End => String::from("end"),
+        Key::PageUp => String::from("pageup"),
+        Key::PageDown => String::from("pagedown"),
+        Key::Delete => String::from("delete"),
+        Key::Insert => String::from("insert"),
+        Key::Null => String::from("null"),
+        Key::Esc => String::from("esc"),
+    }
+}
+
+fn decode(words: &[&str]) -> Option<Event> {
+    match words {
+        ["key", rest @ ..] => decode_key(rest).map(Event::Key),
+        ["mouse", "press", button, rest @ ..] => {
+            let button = match *button {
+                "left" => MouseButton::Left,
+                "right" => MouseButton::Right,
+                "middle" => MouseButton::Middle,
+                "wheelup" => MouseButton::WheelUp,
+                "wheeldown" => MouseButton::WheelDown,
+                _ => return None
+            };
+            match numbers(rest)?[..] {
+                [x, y] => Some(Event::Mouse(MouseEvent::Press(button, x, y))),
+                _ => None
+            }
+        },
+        ["mouse", "release", rest @ ..] => match numbers(rest)?[..] {
+            [x, y] => Some(Event::Mouse(MouseEvent::Release(x, y))),
+            _ => None
+        },
+        ["mouse", "hold", rest @ ..] => match numbers(rest)?[..] {
+            [x, y] => Some(Event::Mouse(MouseEvent::Hold(x, y))),
+            _ => None
+        },
+        ["resize"] => Some(Event::Resize),
+        ["idle"] => Some(Event::Idle),
+        ["unsupported", rest @ ..] => {
+            let codes: Option<Vec<u32>> = rest.iter().map(|w| w.parse().ok()).collect();
+            codes.map(Event::Unsupported)
+        },
+        _ => None
+    }
+}
+
+fn decode_key(words: &[&str]) -> Option<Key> {
+    let (first, rest) = words.split_first()?;
+    let key = match *first {
+        "ctrl" => return decode_key(rest).map(|k| Key::Ctrl(Box::new(k))),
+        "alt" => return decode_key(rest).map(|k| Key::Alt(Box::new(k))),
+        "shift" => return decode_key(rest).map(|k| Key::Shift(Box::new(k))),
+        "backspace" => Key::Backspace,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "null" => Key::Null,
+        "esc" => Key::Esc,
+        w if w.starts_with("U+") => Key::Char(u32::from_str_radix(&w[2..], 16).ok().and_then(::std::char::from_u32)?),
+        w if w.starts_with('f') => Key::F(w[1..].parse().ok()?),
+        _ => return None
+    };
+    if rest.is_empty() {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn recordings_play_back_the_same() {
+        let path = env::temp_dir().join(format!("mfte_session_{}.txt", process::id()));
+        let path = path.to_str().unwrap();
+        let keys = KeybindTable::from("^Q: Quit\nM-S-F5: Undo");
+        let mut r = Recorder::create(path, &keys).unwrap();
+        r.log((80, 24), &Event::Key(Key::Char(' '))).unwrap();
+        r.log((80, 24), &Event::Key(Key::Ctrl(Box::new(Key::Shift(Box::new(Key::Char('s'))))))).unwrap();
+        r.log((100, 30), &Event::Resize).unwrap();
+        r.log((100, 30), &Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, 3, 7))).unwrap();
+        r.log((100, 30), &Event::Key(Key::F(12))).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "bind M-S-F5: Undo\n\
+            bind ^Q: Quit\n\
+            size 80 24\n\
+            key U+0020\n\
+            key ctrl shift U+0073\n\
+            size 100 30\n\
+            resize\n\
+            mouse press wheelup 3 7\n\
+            key f12\n");
+
+        let recording = load(path).unwrap();
+        let (mut binds, mut expected) = (recording.keys.entries(), keys.entries());
+        binds.sort();
+        expected.sort();
+        assert_eq!(binds, expected);
+        assert_eq!(recording.segments.len(), 2);
+        assert_eq!(recording.segments[0].0, (80, 24));
+        let replayed: Vec<String> = recording.segments.iter().flat_map(|s| s.1.iter().map(encode)).collect();
+        assert_eq!(replayed, ["key U+0020", "key ctrl shift U+0073", "resize", "mouse press wheelup 3 7", "key f12"]);
+        fs::remove_file(path).unwrap();
+    }
+}
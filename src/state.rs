@@ -1,32 +1,65 @@
 extern crate clipboard;
 use self::clipboard::{ClipboardProvider, ClipboardContext};
 
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
 use super::keybinds::*;
 use super::file::*;
+use super::terminal::Event;
+use super::search::{Matcher, Replacement};
+
+/// More than this and it's probably a typo, and one that would hang the editor for ages
+const MAX_MACRO_PLAYS: usize = 100_000;
+
+/// Where a command that takes more than one answer is up to
+pub enum Stage {
+    Input,
+    ReplaceWith,
+    ConfirmReplace(Replacement),
+    ConfirmBinary,
+    ConfirmOverwrite(String),
+    ConfirmClose,
+    ConfirmQuit,
+    ConfirmReload,
+    ConfirmStaleSave,
+    ConfirmRecover,
+}
 
 pub struct OneLinerState {
     pub command: Command,
-    pub label: &'static str,
+    pub label: Cow<'static, str>,
     pub file: File,
+    pub stage: Stage,
+    pub regex: bool,
+    pub error: Option<String>,
 }
 
 impl From<Command> for OneLinerState {
     fn from(c: Command) -> OneLinerState {
         let label = match c {
-            Command::Quit | Command::CloseFile | Command::Refresh |
+            Command::Quit | Command::CloseFile | Command::Refresh | Command::Reload | Command::SaveFile |
                 Command::Cut | Command::Copy | Command::Paste |
                 Command::Undo | Command::Redo |
-                Command::NewTab => "",
+                Command::NewTab | Command::LineEndings | Command::RecordMacro |
+                Command::ToggleRegex => "",
             Command::OpenFile => "Open file:",
-            Command::SaveFile => "Save file:",
+            Command::SaveAs => "Save as:",
             Command::Goto => "Jump to line[:col]:",
             Command::Find => "Find text:",
-            Command::FindReplace => "AAAAAAAAAA",
+            Command::FindReplace => "Replace text:",
+            Command::PlayMacro => "Play macro how many times:",
         };
         OneLinerState {
             command: c,
-            label: label,
+            label: label.into(),
             file: File::empty(),
+            stage: Stage::Input,
+            regex: false,
+            error: None,
         }
     }
 }
@@ -36,7 +69,24 @@ impl OneLinerState {
         &self.file.lines[0]
     }
 
-    pub fn tab(&mut self) {
+    /// Switches Find and Replace between plain text and regular expressions
+    pub fn toggle_regex(&mut self) {
+        self.regex = !self.regex;
+        self.label = match (&self.command, self.regex) {
+            (&Command::Find, false) => "Find text:",
+            (&Command::Find, true) => "Find regex:",
+            (_, false) => "Replace text:",
+            (_, true) => "Replace regex:",
+        }.into();
+        self.file.display_dirty = true;
+    }
+
+    /// Whether typing a character answers the prompt instead of inserting it
+    pub fn is_choice(&self) -> bool {
+        !matches!(self.stage, Stage::Input | Stage::ReplaceWith)
+    }
+
+    pub fn tab(&mut self) -> Result<(), String> {
         use std::path::PathBuf;
         let mut path = PathBuf::from(self.value());
         if path.file_name().is_none() {
@@ -44,11 +94,16 @@ impl OneLinerState {
         }
         let mut fragment = String::from("");
         if !path.is_dir() {
-            fragment = path.file_name().unwrap().to_os_string().into_string().unwrap();
+            fragment = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
             path.pop();
+            if path.as_os_str().is_empty() {
+                path.push(".");
+            }
         }
-        let results: Vec<String> = path.read_dir().expect("did not get a dir").filter_map(|e| e.ok().and_then(|e| {
-            let name = e.file_name().into_string().unwrap();
+        let entries = path.read_dir().map_err(|e| format!("Can't look in {}: {}", path.display(), e))?;
+        let results: Vec<String> = entries.filter_map(|e| e.ok().and_then(|e| {
+            // Names that aren't valid Unicode can't be typed in here anyway
+            let name = e.file_name().into_string().ok()?;
             if name.starts_with(&fragment) {
                 Some(name)
             } else {
@@ -65,11 +120,12 @@ impl OneLinerState {
                 if path.is_dir() && results.len() == 1 {
                     path.push("");
                 }
-                self.file.lines[0] = path.into_os_string().into_string().unwrap();
+                self.file.lines[0] = path.to_string_lossy().into_owned();
                 self.file.move_cursor_end((9001, 9001));
                 self.file.display_dirty = true;
             }
         }
+        Ok(())
     }
 }
 
@@ -78,6 +134,20 @@ pub struct EditorState {
     pub files: Vec<File>,
     pub one_liners: Vec<Option<OneLinerState>>,
     pub active_file: usize,
+    /// Something to tell the user, until the next key gets pressed
+    pub message: Option<String>,
+    /// Set once it's time for the editor to exit
+    pub finished: bool,
+    /// Set when the terminal might not be showing what was last sent to it, so all of it gets sent again
+    pub repaint: bool,
+    /// What to carry on with once the file being saved makes it to disk.
+    /// It belongs to the active tab, so it's forgotten whenever that changes.
+    pending: Option<Command>,
+    last_recovery: Instant,
+    /// Everything that's happened since macro recording started, or `None` if it hasn't
+    recording: Option<Vec<Event>>,
+    /// The last macro that was recorded
+    recorded: Vec<Event>,
 }
 
 /* Man, I hate Rust sometimes.
@@ -118,10 +188,56 @@ macro_rules! restrict_func {
 }
 
 impl EditorState {
+    pub fn new(keys: KeybindTable) -> EditorState {
+        EditorState {
+            keys,
+            files: vec![],
+            one_liners: vec![],
+            active_file: 0,
+            message: None,
+            finished: false,
+            repaint: false,
+            pending: None,
+            last_recovery: Instant::now(),
+            recording: None,
+            recorded: vec![],
+        }
+    }
+
     pub fn one_liner_active(&self) -> bool {
         self.one_liner().is_some()
     }
 
+    pub fn one_liner_command(&self) -> Option<Command> {
+        self.one_liner().as_ref().map(|ol| ol.command.clone())
+    }
+
+    pub fn one_liner_is_choice(&self) -> bool {
+        self.one_liner().as_ref().map(|ol| ol.is_choice()).unwrap_or(false)
+    }
+
+    /// Closes the one-liner without acting on it
+    pub fn cancel_one_liner(&mut self) {
+        self.pending = None;
+        if let Some(ol) = self.one_liner_mut().take() {
+            match ol.command {
+                Command::Find => self.active_file_mut().end_search(),
+                Command::FindReplace => {
+                    if let Stage::ConfirmReplace(r) = ol.stage {
+                        self.finish_replace(r);
+                    } else {
+                        self.active_file_mut().end_search();
+                    }
+                },
+                // Not answering is the same as playing it safe
+                Command::OpenFile => if let Stage::ConfirmBinary = ol.stage {
+                    self.active_file_mut().read_only = true;
+                },
+                _ => ()
+            }
+        }
+    }
+
     pub fn consume_one_liner(&mut self) -> Option<(Command, String)> {
         let result = self.one_liner_mut().take().map(|ol| (ol.command.clone(), (*ol.value()).clone()));
         result
@@ -168,11 +284,22 @@ impl EditorState {
         self.active_file().debug(dim)
     }
 
+    /// The message to show instead of the usual status, if there is one
+    pub fn message(&self) -> Option<&String> {
+        self.message.as_ref().or(self.active_file().message.as_ref())
+    }
+
+    pub fn clear_message(&mut self) {
+        self.message = None;
+        self.active_file_mut().message = None;
+    }
+
     pub fn chunked_text(&self, dim: (i32, i32)) -> Vec<(Option<u16>, Vec<TextChunk>)> {
         self.active_file().chunked_text(dim)
     }
 
     pub fn refresh(&mut self, dim: (i32, i32)) {
+        self.repaint = true;
         self.active_file_mut().refresh(dim)
     }
 
@@ -180,38 +307,147 @@ impl EditorState {
         self.active_file += 1;
         self.files.insert(self.active_file, File::empty());
         self.one_liners.insert(self.active_file, None);
+        self.arrive_at_tab();
     }
 
     pub fn close_tab(&mut self) {
+        self.active_file_mut().forget_recovery();
         self.files.remove(self.active_file);
         self.one_liners.remove(self.active_file);
         if self.files.len() > 0 {
             self.active_file %= self.files.len();
+            self.arrive_at_tab();
+        } else {
+            self.finished = true;
+        }
+    }
+
+    /// Closes the active tab, asking what to do about unsaved changes first
+    pub fn close_file(&mut self) {
+        if self.active_file().dirty() {
+            let mut ol = OneLinerState::from(Command::CloseFile);
+            ol.label = "Unsaved changes. Save, discard or cancel? (s/d/c)".into();
+            ol.stage = Stage::ConfirmClose;
+            self.set_one_liner(ol);
+        } else {
+            self.close_tab();
+        }
+    }
+
+    /// Exits, asking what to do about unsaved changes first
+    pub fn quit(&mut self) {
+        match self.files.iter().filter(|f| f.dirty()).count() {
+            0 => self.finished = true,
+            n => {
+                let mut ol = OneLinerState::from(Command::Quit);
+                ol.label = match n {
+                    1 => String::from("1 file has unsaved changes. Save, discard or cancel? (s/d/c)"),
+                    n => format!("{} files have unsaved changes. Save all, discard or cancel? (s/d/c)", n)
+                }.into();
+                ol.stage = Stage::ConfirmQuit;
+                self.set_one_liner(ol);
+            }
+        }
+    }
+
+    /// Saves every file with unsaved changes and then exits, stopping to ask for a name
+    /// whenever a file doesn't have one yet
+    fn save_all_and_quit(&mut self) {
+        for i in 0..self.files.len() {
+            if !self.files[i].dirty() {
+                continue;
+            }
+            self.active_file = i;
+            self.cancel_one_liner();
+            if let Err(e) = self.save_file() {
+                self.message = Some(e);
+                return;
+            }
+            // Still needs a name, or the go-ahead to overwrite
+            if self.one_liner_active() {
+                self.pending = Some(Command::Quit);
+                return;
+            }
+        }
+        self.finished = true;
+    }
+
+    fn resume_pending(&mut self) {
+        match self.pending.take() {
+            Some(Command::CloseFile) => self.close_tab(),
+            Some(Command::Quit) => self.save_all_and_quit(),
+            _ => ()
         }
     }
 
     pub fn next_tab(&mut self) {
-        self.active_file = (self.active_file + 1) % self.files.len();
+        let next = (self.active_file + 1) % self.files.len();
+        self.switch_tab(next);
+    }
+
+    pub fn switch_tab(&mut self, index: usize) {
+        if index >= self.files.len() {
+            return;
+        }
+        self.active_file = index;
+        self.arrive_at_tab();
+    }
+
+    /// Catches up after a different tab becomes the active one: whatever was pending belonged
+    /// to the old tab, and the new one might have changed on disk while nobody was looking at it
+    fn arrive_at_tab(&mut self) {
+        self.pending = None;
+        if self.active_file().unseen_change_on_disk() && !self.one_liner_active() {
+            let mut ol = OneLinerState::from(Command::Reload);
+            ol.label = "File changed on disk. Reload? (y/n)".into();
+            ol.stage = Stage::ConfirmReload;
+            self.set_one_liner(ol);
+        }
     }
 
-    pub fn cut(&mut self, dim: (i32, i32)) {
+    /// Reads the active file from disk again, checking first if that would throw away changes
+    pub fn reload(&mut self, dim: (i32, i32)) -> Result<(), String> {
+        if self.active_file().dirty() {
+            let mut ol = OneLinerState::from(Command::Reload);
+            ol.label = "Discard unsaved changes and reload? (y/n)".into();
+            ol.stage = Stage::ConfirmReload;
+            self.set_one_liner(ol);
+            Ok(())
+        } else {
+            self.reload_unchecked(dim)
+        }
+    }
+
+    fn reload_unchecked(&mut self, dim: (i32, i32)) -> Result<(), String> {
+        let name = self.active_file().name.clone();
+        self.active_file_mut().reload(dim).map_err(|e| format!("Could not reload {}: {}", name, e))?;
+        self.message = Some(format!("Reloaded {}", name));
+        Ok(())
+    }
+
+    pub fn cut(&mut self, dim: (i32, i32)) -> Result<(), String> {
         if self.active_file().has_selection() {
-            self.copy(dim);
+            self.copy(dim)?;
+            self.begin_group();
             self.delete(dim);
+            self.end_group();
         }
+        Ok(())
     }
 
-    pub fn copy(&mut self, _: (i32, i32)) {
+    pub fn copy(&mut self, _: (i32, i32)) -> Result<(), String> {
         if self.active_file().has_selection() {
             let selection = self.active_file().selected_text();
-            let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-            ctx.set_contents(selection).unwrap();
+            let mut ctx: ClipboardContext = ClipboardProvider::new().map_err(|e| format!("No clipboard: {}", e))?;
+            ctx.set_contents(selection).map_err(|e| format!("Could not copy: {}", e))?;
         }
+        Ok(())
     }
 
-    pub fn paste(&mut self, dim: (i32, i32)) {
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        let clipboard = ctx.get_contents().unwrap();
+    pub fn paste(&mut self, dim: (i32, i32)) -> Result<(), String> {
+        let mut ctx: ClipboardContext = ClipboardProvider::new().map_err(|e| format!("No clipboard: {}", e))?;
+        let clipboard = ctx.get_contents().map_err(|e| format!("Could not paste: {}", e))?;
+        self.begin_group();
         for c in clipboard.chars() {
             if c == '\n' {
                 self.insert_newline(dim, false);
@@ -219,6 +455,110 @@ impl EditorState {
                 self.insert(dim, c);
             }
         }
+        self.end_group();
+        Ok(())
+    }
+
+    /// Starts recording a macro, or finishes the one being recorded
+    pub fn toggle_macro_recording(&mut self) {
+        match self.recording.take() {
+            Some(events) => {
+                self.message = Some(format!("Recorded a macro of {} keys", events.len()));
+                self.recorded = events;
+            },
+            None => self.recording = Some(vec![])
+        }
+    }
+
+    pub fn recording_macro(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Adds an event to the macro being recorded, if there is one, leaving out
+    /// the keys that start and stop macros themselves
+    pub fn capture(&mut self, e: &Event) {
+        if let Some(ref mut events) = self.recording {
+            if let Event::Key(ref k) = *e {
+                if let Some(Command::RecordMacro) | Some(Command::PlayMacro) = self.keys.lookup(k.clone()) {
+                    return;
+                }
+            }
+            events.push(e.clone());
+        }
+    }
+
+    /// Asks how many times to play the macro back
+    pub fn ask_macro_count(&mut self) -> Result<(), String> {
+        if self.recording.is_some() {
+            return Err(String::from("Can't play a macro while recording one"));
+        }
+        if self.recorded.is_empty() {
+            return Err(String::from("No macro to play"));
+        }
+        self.set_one_liner(OneLinerState::from(Command::PlayMacro));
+        Ok(())
+    }
+
+    /// The macro to play and how many times to play it, going by what was typed in the one-liner
+    pub fn macro_playback(&self, count: &str) -> Result<(Vec<Event>, usize), String> {
+        let count = match count.trim() {
+            "" => 1,
+            n => n.parse().map_err(|_| format!("Not a number: {}", count))?
+        };
+        if count > MAX_MACRO_PLAYS {
+            return Err(format!("Can't play a macro more than {} times", MAX_MACRO_PLAYS));
+        }
+        // Undoing inside the macro's own undo step would leave that step meaning nothing
+        let undoes = self.recorded.iter().any(|e| match *e {
+            Event::Key(ref k) => matches!(self.keys.lookup(k.clone()), Some(Command::Undo) | Some(Command::Redo)),
+            _ => false
+        });
+        if undoes {
+            return Err(String::from("Can't play a macro that undoes or redoes"));
+        }
+        Ok((self.recorded.clone(), count))
+    }
+
+    /// Where each file's caret is and how many changes it's seen, to notice a macro
+    /// going around without doing anything
+    pub fn playback_position(&self) -> (usize, Vec<(u64, Cursor)>) {
+        (self.active_file, self.files.iter().map(|f| (f.version(), f.caret.clone())).collect())
+    }
+
+    /// Makes everything a macro does undo as a single step, in whichever files it touches.
+    /// Gets called before each step, so files the macro never gets to are left alone.
+    pub fn continue_playback(&mut self) {
+        let f = self.active_file_mut();
+        if !f.in_group() {
+            f.begin_group();
+        }
+    }
+
+    pub fn end_playback(&mut self) {
+        for f in &mut self.files {
+            if f.in_group() {
+                f.end_group();
+            }
+        }
+    }
+
+    /// Whether the active file is looking for something that isn't there
+    pub fn search_failed(&self) -> bool {
+        self.active_file().search_failed()
+    }
+
+    pub fn begin_group(&mut self) {
+        if let Some(ref mut ols) = *self.one_liner_mut() {
+            return ols.file.begin_group();
+        }
+        self.active_file_mut().begin_group();
+    }
+
+    pub fn end_group(&mut self) {
+        if let Some(ref mut ols) = *self.one_liner_mut() {
+            return ols.file.end_group();
+        }
+        self.active_file_mut().end_group();
     }
 
     pub fn select(&mut self) {
@@ -238,21 +578,271 @@ impl EditorState {
     }
 
     pub fn display_dirty(&self) -> bool {
-        match self.one_liner() {
-            &Some(ref ols) => ols.file.display_dirty,
-            _ => self.active_file().display_dirty
+        match *self.one_liner() {
+            Some(ref ols) => ols.file.display_dirty || self.active_file().display_dirty,
+            None => self.active_file().display_dirty
         }
     }
 
     pub fn clean_display(&mut self) {
-        match self.one_liner_mut() {
-            &mut Some(ref mut ols) => {
-                ols.file.display_dirty = false;
+        if let Some(ref mut ols) = *self.one_liner_mut() {
+            ols.file.display_dirty = false;
+        }
+        self.active_file_mut().display_dirty = false;
+    }
+
+    pub fn begin_search(&mut self) {
+        self.active_file_mut().begin_search();
+    }
+
+    /// Keeps the active file's search in step with what's typed in the Find one-liner
+    pub fn update_search(&mut self, dim: (i32, i32)) {
+        let matcher = match *self.one_liner_mut() {
+            Some(ref mut ols @ OneLinerState { command: Command::Find, .. }) |
+                Some(ref mut ols @ OneLinerState { command: Command::FindReplace, stage: Stage::Input, .. }) => {
+                let matcher = if ols.regex {
+                    Matcher::regex(ols.value())
+                } else {
+                    Ok(Matcher::new(ols.value()))
+                };
+                let error = matcher.as_ref().err().cloned();
+                if error != ols.error {
+                    ols.error = error;
+                    ols.file.display_dirty = true;
+                }
+                match matcher {
+                    Ok(m) => m,
+                    Err(_) => return
+                }
+            },
+            _ => return
+        };
+        self.active_file_mut().search(dim, matcher);
+    }
+
+    pub fn find_next(&mut self, dim: (i32, i32)) {
+        self.active_file_mut().find_next(dim);
+    }
+
+    /// Moves the find and replace one-liner on to its next question
+    pub fn advance_replace(&mut self) {
+        let ol = match self.one_liner_mut().take() {
+            Some(ol) => ol,
+            None => return
+        };
+        let mut next = OneLinerState::from(Command::FindReplace);
+        match ol.stage {
+            Stage::Input => {
+                if ol.error.is_some() {
+                    self.set_one_liner(ol);
+                    return;
+                }
+                next.label = if ol.regex { "Replace with ($1 for groups):" } else { "Replace with:" }.into();
+                next.stage = Stage::ReplaceWith;
+            },
+            Stage::ReplaceWith => {
+                let file = self.active_file();
+                if file.at_match() {
+                    let here = (file.caret.y, file.caret.x);
+                    next.label = "Replace this one? (y/n/a/q)".into();
+                    next.stage = Stage::ConfirmReplace(Replacement::new(ol.value(), here));
+                } else {
+                    self.finish_replace(Replacement::new("", (0, 0)));
+                    return;
+                }
+            },
+            _ => next = ol,
+        }
+        self.set_one_liner(next);
+    }
+
+    /// Checks with the user about anything odd in a file that was just opened
+    pub fn check_opened(&mut self) {
+        if self.active_file().has_recovery() {
+            let mut ol = OneLinerState::from(Command::OpenFile);
+            ol.label = "Found unsaved changes from last time. Restore them? (y/n)".into();
+            ol.stage = Stage::ConfirmRecover;
+            self.set_one_liner(ol);
+        } else {
+            self.warn_if_binary();
+        }
+    }
+
+    /// Keeps recovery copies of unsaved changes up to date, every so often
+    pub fn write_recovery(&mut self) {
+        if self.last_recovery.elapsed() < Duration::from_secs(2) {
+            return;
+        }
+        self.last_recovery = Instant::now();
+        for f in self.files.iter_mut() {
+            if let Err(e) = f.write_recovery() {
+                self.message = Some(format!("Could not keep a recovery copy of {}: {}", f.name, e));
+            }
+        }
+    }
+
+    /// Cleans up recovery copies on the way out, since by then everything has been saved or thrown away
+    pub fn forget_recovery(&mut self) {
+        for f in self.files.iter_mut() {
+            f.forget_recovery();
+        }
+    }
+
+    /// Writes every unsaved file somewhere safe, returning where they went
+    pub fn dump_recovery(&self) -> Vec<String> {
+        self.files.iter().enumerate().filter(|&(_, f)| f.dirty()).filter_map(|(i, f)| {
+            let name = if f.has_path() {
+                f.name.clone()
+            } else {
+                format!("untitled-{}-{}", process::id(), i)
+            };
+            f.dump_recovery(&name).ok().map(|p| p.display().to_string())
+        }).collect()
+    }
+
+    /// Asks whether a file that doesn't look like text should be opened read-only
+    pub fn warn_if_binary(&mut self) {
+        if self.active_file().binary {
+            let mut ol = OneLinerState::from(Command::OpenFile);
+            ol.label = "Binary file. Open read-only? (y/n)".into();
+            ol.stage = Stage::ConfirmBinary;
+            self.set_one_liner(ol);
+        }
+    }
+
+    /// Answers whichever yes-or-no question the one-liner is asking
+    pub fn choose(&mut self, dim: (i32, i32), c: char) {
+        let c = c.to_lowercase().next().unwrap_or(c);
+        let mut r = match self.one_liner_mut().take() {
+            Some(OneLinerState { stage: Stage::ConfirmReplace(r), .. }) => r,
+            Some(OneLinerState { stage: Stage::ConfirmOverwrite(path), .. }) => {
+                match c {
+                    'y' => if let Err(e) = self.save_as_unchecked(&path) {
+                        self.message = Some(e);
+                    },
+                    'n' => self.ask_save_as(&path),
+                    _ => self.confirm_overwrite(path)
+                }
                 return;
             },
-            _ => ()
+            Some(OneLinerState { stage: Stage::ConfirmClose, .. }) => {
+                match c {
+                    's' => {
+                        self.pending = Some(Command::CloseFile);
+                        if let Err(e) = self.save_file() {
+                            self.message = Some(e);
+                        }
+                    },
+                    'd' => self.close_tab(),
+                    'c' | '\n' => (),
+                    _ => self.close_file()
+                }
+                return;
+            },
+            Some(OneLinerState { stage: Stage::ConfirmQuit, .. }) => {
+                match c {
+                    's' => self.save_all_and_quit(),
+                    'd' => self.finished = true,
+                    'c' | '\n' => (),
+                    _ => self.quit()
+                }
+                return;
+            },
+            Some(ol @ OneLinerState { stage: Stage::ConfirmReload, .. }) => {
+                match c {
+                    'y' => if let Err(e) = self.reload_unchecked(dim) {
+                        self.message = Some(e);
+                    },
+                    'n' | '\n' => self.active_file_mut().ignore_change_on_disk(),
+                    _ => self.set_one_liner(ol)
+                }
+                return;
+            },
+            Some(ol @ OneLinerState { stage: Stage::ConfirmStaleSave, .. }) => {
+                match c {
+                    'y' => if let Err(e) = self.save_file_anyway() {
+                        self.message = Some(e);
+                    },
+                    'n' | '\n' => self.pending = None,
+                    _ => self.set_one_liner(ol)
+                }
+                return;
+            },
+            Some(ol @ OneLinerState { stage: Stage::ConfirmRecover, .. }) => {
+                match c {
+                    'y' => match self.active_file_mut().recover(dim) {
+                        Ok(()) => self.message = Some(String::from("Restored unsaved changes")),
+                        Err(e) => self.message = Some(format!("Could not restore unsaved changes: {}", e))
+                    },
+                    'n' => self.active_file_mut().discard_recovery(),
+                    _ => return self.set_one_liner(ol)
+                }
+                self.warn_if_binary();
+                return;
+            },
+            Some(OneLinerState { stage: Stage::ConfirmBinary, .. }) => {
+                match c {
+                    'y' | '\n' => self.active_file_mut().read_only = true,
+                    'n' => (),
+                    _ => self.warn_if_binary()
+                }
+                return;
+            },
+            other => {
+                *self.one_liner_mut() = other;
+                return;
+            }
+        };
+        let keep_going = match c {
+            'y' => self.replace_one(dim, &mut r, true),
+            'n' => self.replace_one(dim, &mut r, false),
+            'a' => {
+                self.active_file_mut().begin_group();
+                while self.replace_one(dim, &mut r, true) {}
+                self.active_file_mut().end_group();
+                false
+            },
+            'q' => false,
+            _ => true
+        };
+        if keep_going {
+            let mut ol = OneLinerState::from(Command::FindReplace);
+            ol.label = "Replace this one? (y/n/a/q)".into();
+            ol.stage = Stage::ConfirmReplace(r);
+            self.set_one_liner(ol);
+        } else {
+            self.finish_replace(r);
         }
-        self.active_file_mut().display_dirty = false;
+    }
+
+    /// Replaces (or skips) the match under the caret and moves to the next one,
+    /// returning whether there's another match to ask about
+    fn replace_one(&mut self, dim: (i32, i32), r: &mut Replacement, replace: bool) -> bool {
+        let file = self.active_file_mut();
+        let mut inclusive = false;
+        if replace {
+            if let Some((at, old_len, new_len)) = file.replace_match(dim, &r.text) {
+                r.replaced(at, old_len, new_len);
+                // An empty match would just match again right where it was replaced
+                inclusive = old_len > 0;
+            }
+        }
+        let from = (file.caret.y, file.caret.x);
+        if !file.find_from(dim, from, inclusive) {
+            return false;
+        }
+        let next = (file.caret.y, file.caret.x);
+        let wrapped = if inclusive { next < from } else { next <= from };
+        r.advance(next, wrapped)
+    }
+
+    fn finish_replace(&mut self, r: Replacement) {
+        let file = self.active_file_mut();
+        file.end_search();
+        self.message = Some(match r.count {
+            1 => String::from("Replaced 1 occurrence"),
+            n => format!("Replaced {} occurrences", n)
+        });
     }
 
     split_func!(move_cursor_left);
@@ -284,16 +874,17 @@ impl EditorState {
         }
     }
 
-    pub fn goto(&mut self, dim: (i32, i32), target: &str) {
+    pub fn goto(&mut self, dim: (i32, i32), target: &str) -> Result<(), String> {
+        let number = |s: &str| s.trim().parse::<i32>().map_err(|_| format!("Not a line[:col]: {}", target));
         let colon_idx = target.find(':');
         let (row, col) = match colon_idx {
             Some(n) => {
                 let (row_str, col_str) = target.split_at(n);
                 let (_, col_str) = col_str.split_at(1);
-                (i32::from_str_radix(row_str, 10).unwrap(), i32::from_str_radix(col_str, 10).unwrap())
+                (number(row_str)?, number(col_str)?)
             },
             None => {
-                (i32::from_str_radix(target, 10).unwrap(), self.active_file().caret.x)
+                (number(target)?, self.active_file().caret.x)
             }
         };
         let row = if row < 1 {
@@ -304,6 +895,7 @@ impl EditorState {
             row
         };
         self.active_file_mut().goto(dim, (row, col));
+        Ok(())
     }
 
     pub fn insert_newline(&mut self, dim: (i32, i32), indent: bool) {
@@ -315,7 +907,10 @@ impl EditorState {
 
     split_func!(delete);
     split_func!(backspace);
+    split_func!(undo);
+    split_func!(redo);
     restrict_func!(tab);
+    restrict_func!(convert_line_endings);
 
     pub fn insert(&mut self, dim: (i32, i32), c: char) {
         match self.one_liner_mut() {
@@ -325,11 +920,169 @@ impl EditorState {
         self.active_file_mut().insert(dim, c)
     }
 
-    pub fn save_file(&mut self, path: &str) {
-        self.active_file_mut().save(path);
+    /// Saves the active file where it already lives, or asks where to put it if it's never been saved
+    pub fn save_file(&mut self) -> Result<(), String> {
+        if !self.active_file().has_path() {
+            self.ask_save_as("");
+            Ok(())
+        } else if self.active_file().changed_on_disk() {
+            self.confirm_stale_save();
+            Ok(())
+        } else {
+            self.save_file_anyway()
+        }
     }
 
-    pub fn open_file(&mut self, path: &str) {
-        self.files[self.active_file] = File::open(path);
+    fn save_file_anyway(&mut self) -> Result<(), String> {
+        let path = self.active_file().name.clone();
+        let result = self.active_file_mut().save(&path);
+        self.report_save(&path, result)
+    }
+
+    fn confirm_stale_save(&mut self) {
+        let mut ol = OneLinerState::from(Command::SaveFile);
+        ol.label = "File changed on disk since it was opened. Overwrite anyway? (y/n)".into();
+        ol.stage = Stage::ConfirmStaleSave;
+        self.set_one_liner(ol);
+    }
+
+    pub fn ask_save_as(&mut self, path: &str) {
+        let mut ol = OneLinerState::from(Command::SaveAs);
+        ol.file.lines[0] = String::from(path);
+        ol.file.move_cursor_end((9001, 9001));
+        self.set_one_liner(ol);
+    }
+
+    /// Saves the active file somewhere new, checking first if that would clobber some other file,
+    /// or this one if it changed on disk
+    pub fn save_as(&mut self, path: &str) -> Result<(), String> {
+        if self.active_file().is_at(path) {
+            if self.active_file().changed_on_disk() {
+                self.confirm_stale_save();
+                Ok(())
+            } else {
+                self.save_as_unchecked(path)
+            }
+        } else if Path::new(path).exists() {
+            self.confirm_overwrite(String::from(path));
+            Ok(())
+        } else {
+            self.save_as_unchecked(path)
+        }
+    }
+
+    fn save_as_unchecked(&mut self, path: &str) -> Result<(), String> {
+        let result = self.active_file_mut().save_as(path);
+        self.report_save(path, result)
+    }
+
+    fn report_save(&mut self, path: &str, result: io::Result<()>) -> Result<(), String> {
+        if let Err(e) = result {
+            self.pending = None;
+            return Err(format!("Could not save {}: {}", path, e));
+        }
+        self.message = Some(format!("Saved {}", path));
+        self.resume_pending();
+        Ok(())
+    }
+
+    fn confirm_overwrite(&mut self, path: String) {
+        let mut ol = OneLinerState::from(Command::SaveAs);
+        ol.label = "File exists. Overwrite? (y/n)".into();
+        ol.stage = Stage::ConfirmOverwrite(path);
+        self.set_one_liner(ol);
+    }
+
+    pub fn open_file(&mut self, path: &str) -> Result<(), String> {
+        self.pending = None;
+        self.files[self.active_file] = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+        self.check_opened();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> EditorState {
+        let mut s = EditorState::new(KeybindTable::default());
+        s.files.push(File::empty());
+        s.one_liners.push(None);
+        s
+    }
+
+    #[test]
+    fn mistakes_are_errors_not_crashes() {
+        let mut s = state();
+        assert!(s.goto((80, 24), "abc").is_err());
+        assert!(s.goto((80, 24), "1:x").is_err());
+        assert!(s.goto((80, 24), "1:1").is_ok());
+        assert!(s.open_file("src").is_err());
+        let mut ol = OneLinerState::from(Command::OpenFile);
+        ol.file.lines[0] = String::from("no/such/dir/file");
+        assert!(ol.tab().is_err());
+    }
+
+    #[test]
+    fn unsaved_changes_are_confirmed() {
+        let mut s = state();
+        s.insert((80, 24), 'x');
+        s.quit();
+        assert!(!s.finished && s.one_liner_is_choice());
+        s.choose((80, 24), 'c');
+        assert!(!s.finished && !s.one_liner_active());
+        s.close_file();
+        s.choose((80, 24), 's');
+        assert!(matches!(s.one_liner_command(), Some(Command::SaveAs)));
+        let path = ::std::env::temp_dir().join(format!("mfte_close_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        s.consume_one_liner();
+        s.save_as(path).unwrap();
+        assert!(s.finished && s.files.is_empty());
+        ::std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pending_saves_stay_with_their_tab() {
+        let mut s = state();
+        s.insert((80, 24), 'x');
+        s.close_file();
+        s.choose((80, 24), 's');
+        s.new_tab();
+        s.insert((80, 24), 'y');
+        let path = ::std::env::temp_dir().join(format!("mfte_pending_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        s.save_as(path).unwrap();
+        assert!(!s.finished);
+        assert_eq!(s.files.len(), 2);
+        ::std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn changes_on_disk_are_noticed() {
+        let dim = (80, 24);
+        let dir = ::std::env::temp_dir();
+        let a = dir.join(format!("mfte_disk_a_{}.txt", ::std::process::id()));
+        let b = dir.join(format!("mfte_disk_b_{}.txt", ::std::process::id()));
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        ::std::fs::write(a, "a\n").unwrap();
+        ::std::fs::write(b, "b\n").unwrap();
+        let mut s = EditorState::new(KeybindTable::default());
+        for path in &[a, b] {
+            s.files.push(File::open(path).unwrap());
+            s.one_liners.push(None);
+        }
+        ::std::fs::write(b, "bee\n").unwrap();
+        s.close_tab();
+        assert!(matches!(s.one_liner_command(), Some(Command::Reload)));
+        s.choose(dim, 'n');
+        s.insert(dim, 'x');
+        s.save_as(b).unwrap();
+        assert!(matches!(s.one_liner_command(), Some(Command::SaveFile)));
+        s.choose(dim, 'y');
+        assert_eq!(::std::fs::read_to_string(b).unwrap(), "xb\n");
+        ::std::fs::remove_file(a).unwrap();
+        ::std::fs::remove_file(b).unwrap();
     }
 }
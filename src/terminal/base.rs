@@ -1,5 +1,10 @@
 use std::io::Write;
+use std::time::Duration;
 
+/// How long input has to stay quiet before the terminal hands out an `Event::Idle`
+pub const IDLE_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Color {
     Reset,
     Black,
@@ -10,9 +15,13 @@ pub enum Color {
 // Shamelessly stolen from termios
 // which doesn't compile on Win32
 // which is why I'm doing all this nonsense in the first place
+#[derive(Clone)]
 pub enum Event {
     Key(Key),
     Mouse(MouseEvent),
+    Resize,
+    /// Nothing happened for a while, which is a good time for background work
+    Idle,
     Unsupported(Vec<u32>),
 }
 
@@ -58,6 +67,7 @@ impl Key {
 }
 
 // Also termios
+#[derive(Clone)]
 pub enum MouseEvent {
     Press(MouseButton, i32, i32),
     Release(i32, i32),
@@ -65,6 +75,7 @@ pub enum MouseEvent {
 }
 
 // Still termios
+#[derive(Clone)]
 pub enum MouseButton {
     Left,
     Right,
@@ -0,0 +1,141 @@
+use std::io::{self, Write};
+use std::mem;
+use std::vec;
+
+use super::base::*;
+use super::super::grapheme::{self, UnicodeSegmentation};
+
+/// A pretend terminal that keeps what's drawn on it in memory and plays back
+/// whatever events it's been handed, so the whole editor can run without a real one
+pub struct Headless {
+    size: (i32, i32),
+    /// Each cell's text and colors, with empty text for the right half of a wide character
+    cells: Vec<(String, Color, Color)>,
+    cursor: (i32, i32),
+    fg: Color,
+    bg: Color,
+    script: Vec<Event>,
+}
+
+impl Headless {
+    pub fn new(size: (i32, i32)) -> Headless {
+        Headless {
+            size,
+            cells: vec![(String::from(" "), Color::Reset, Color::Reset); (size.0 * size.1) as usize],
+            cursor: (1, 1),
+            fg: Color::Reset,
+            bg: Color::Reset,
+            script: vec![],
+        }
+    }
+
+    /// Starts over with a blank screen of a different size
+    pub fn resize(&mut self, size: (i32, i32)) {
+        if size != self.size {
+            *self = Headless {
+                script: mem::take(&mut self.script),
+                ..Headless::new(size)
+            };
+        }
+    }
+
+    /// Queues up an event for `keys` to hand out
+    pub fn send(&mut self, e: Event) {
+        self.script.push(e);
+    }
+
+    /// Everything that's been queued up so far, which runs out when the script does
+    pub fn keys(&mut self) -> vec::IntoIter<Event> {
+        mem::take(&mut self.script).into_iter()
+    }
+
+    fn index(&self, (x, y): (i32, i32)) -> Option<usize> {
+        if x < 1 || y < 1 || x > self.size.0 || y > self.size.1 {
+            None
+        } else {
+            Some(((y - 1) * self.size.0 + (x - 1)) as usize)
+        }
+    }
+}
+
+/// Typing and reading back the screen, for tests
+#[cfg(test)]
+impl Headless {
+    /// Queues up typing some text, one key at a time
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.chars() {
+            self.send(Event::Key(Key::Char(c)));
+        }
+    }
+
+    /// The text on one row of the screen, without the blank space at the end
+    pub fn row(&self, y: i32) -> String {
+        let start = ((y - 1) * self.size.0) as usize;
+        let row: String = self.cells[start..start + self.size.0 as usize].iter()
+            .map(|c| c.0.as_str()).collect();
+        row.trim_end().to_string()
+    }
+
+    pub fn colors_at(&self, pos: (i32, i32)) -> (Color, Color) {
+        let i = self.index(pos).expect("that's not on the screen");
+        (self.cells[i].1, self.cells[i].2)
+    }
+
+    pub fn cursor(&self) -> (i32, i32) {
+        self.cursor
+    }
+}
+
+impl Default for Headless {
+    fn default() -> Headless {
+        Headless::new((80, 24))
+    }
+}
+
+impl Write for Headless {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(data);
+        for g in text.graphemes(true) {
+            let width = grapheme::display_width(g) as i32;
+            let (x, y) = self.cursor;
+            if let Some(i) = self.index((x, y)) {
+                self.cells[i] = (String::from(g), self.fg, self.bg);
+                for dx in 1..width {
+                    if let Some(j) = self.index((x + dx, y)) {
+                        self.cells[j] = (String::new(), self.fg, self.bg);
+                    }
+                }
+            }
+            self.cursor.0 += width;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TermImpl for Headless {
+    fn get_size(&self) -> (i32, i32) {
+        self.size
+    }
+
+    fn goto(&mut self, pos: (i32, i32)) {
+        self.cursor = pos;
+    }
+
+    fn set_color_fg(&mut self, c: Color) {
+        self.fg = c;
+    }
+
+    fn set_color_bg(&mut self, c: Color) {
+        self.bg = c;
+    }
+
+    fn clear(&mut self) {
+        for cell in &mut self.cells {
+            *cell = (String::from(" "), Color::Reset, Color::Reset);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 mod base;
 pub use self::base::*;
 
+mod headless;
+pub use self::headless::*;
+
 #[cfg(windows)]
 mod win32;
 #[cfg(windows)]
@@ -1,4 +1,5 @@
 extern crate termion;
+extern crate signal_hook;
 
 use self::termion::event;
 use self::termion::raw::{RawTerminal, IntoRawMode};
@@ -6,8 +7,12 @@ use self::termion::screen::AlternateScreen;
 use self::termion::input::TermRead;
 use self::termion::cursor;
 use self::termion::color;
+use self::signal_hook::consts::SIGWINCH;
+use self::signal_hook::iterator::Signals;
 use std::io::{self, Write};
-use std::iter;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
 
 use super::base::*;
 
@@ -36,6 +41,7 @@ impl From<event::Key> for Key {
             event::Key::PageDown => Key::PageDown,
             event::Key::Delete => Key::Delete,
             event::Key::Insert => Key::Insert,
+            event::Key::BackTab => Key::Shift(Box::new(Key::Char('\t'))),
             event::Key::F(n) => Key::F(n),
             event::Key::Char(c) => Key::Char(c),
             event::Key::Alt(c) => Key::Alt(Box::new(Key::Char(c))),
@@ -74,32 +80,140 @@ impl From<event::Event> for Event {
         match e {
             event::Event::Key(k) => Event::Key(k.into()),
             event::Event::Mouse(m) => Event::Mouse(m.into()),
-            event::Event::Unsupported(v) => {
-                let vals = v.iter().map(|x| *x as u32).collect();
-                if vals == [27, 91, 49, 59, 50, 65] { // <Esc>[1;2A
-                    Event::Key(Key::Shift(Box::new(Key::Up)))
-                } else if vals == [27, 91, 49, 59, 50, 66] { // <Esc>[1;2B
-                    Event::Key(Key::Shift(Box::new(Key::Down)))
-                } else if vals == [27, 91, 49, 59, 50, 67] { // <Esc>[1;2C
-                    Event::Key(Key::Shift(Box::new(Key::Right)))
-                } else if vals == [27, 91, 49, 59, 50, 68] { // <Esc>[1;2D
-                    Event::Key(Key::Shift(Box::new(Key::Left)))
-                } else {
-                    Event::Unsupported(vals)
-                }
+            event::Event::Unsupported(v) => match modified_key(&v) {
+                Some(k) => Event::Key(k),
+                None => Event::Unsupported(v.iter().map(|x| *x as u32).collect())
             }
         }
     }
 }
 
+/// Termion gives up on keys with modifiers, which xterm and friends send as `<Esc>[1;2A` (Shift+Up),
+/// `<Esc>[3;5~` (Ctrl+Delete) and so on, with the modifiers as one more than Shift=1 + Alt=2 + Ctrl=4.
+fn modified_key(seq: &[u8]) -> Option<Key> {
+    if seq.len() < 3 || &seq[..2] != b"\x1B[" {
+        return None;
+    }
+    let (params, last) = seq[2..].split_at(seq.len() - 3);
+    let params: Vec<u8> = String::from_utf8_lossy(params).split(';').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    let (code, mods) = match params[..] {
+        [code, mods] if mods > 1 => (code, mods - 1),
+        _ => return None
+    };
+    let mut key = match (last[0], code) {
+        (b'A', 1) => Key::Up,
+        (b'B', 1) => Key::Down,
+        (b'C', 1) => Key::Right,
+        (b'D', 1) => Key::Left,
+        (b'H', 1) => Key::Home,
+        (b'F', 1) => Key::End,
+        (c @ b'P'..=b'S', 1) => Key::F(1 + c - b'P'),
+        (b'~', 1) | (b'~', 7) => Key::Home,
+        (b'~', 2) => Key::Insert,
+        (b'~', 3) => Key::Delete,
+        (b'~', 4) | (b'~', 8) => Key::End,
+        (b'~', 5) => Key::PageUp,
+        (b'~', 6) => Key::PageDown,
+        (b'~', n @ 11..=15) => Key::F(n - 10),
+        (b'~', n @ 17..=21) => Key::F(n - 11),
+        (b'~', n @ 23..=24) => Key::F(n - 12),
+        _ => return None
+    };
+    if mods & 1 != 0 {
+        key = Key::Shift(Box::new(key));
+    }
+    if mods & 2 != 0 {
+        key = Key::Alt(Box::new(key));
+    }
+    if mods & 4 != 0 {
+        key = Key::Ctrl(Box::new(key));
+    }
+    Some(key)
+}
+
+/// Raw mode and the alternate screen both get undone when this is dropped. It lives out here
+/// instead of in the `Terminal` so a panic can drop it from anywhere.
+static SCREEN: Mutex<Option<AlternateScreen<RawTerminal<io::Stdout>>>> = Mutex::new(None);
+
+/// Leaves the alternate screen and goes back to cooked mode, if that hasn't happened already
+pub fn restore() {
+    let screen = SCREEN.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(mut screen) = screen {
+        let _ = write!(screen, "{}{}", color::Fg(color::Reset), color::Bg(color::Reset));
+        drop(screen);
+        let _ = io::stdout().flush();
+    }
+}
+
 pub struct Terminal {
-    out: AlternateScreen<RawTerminal<io::Stdout>>,
+    out: io::Stdout,
+}
+
+/// What the input threads pass along to the editor
+enum Input {
+    Event(Event),
+    /// There's no more input coming
+    End,
+    /// The thread panicked, which shouldn't look like the input just ran out
+    Crashed,
+}
+
+/// Input and resizes, merged together from the threads waiting on each of them
+pub struct TerminalKeyStream {
+    events: Receiver<Input>,
+}
+
+impl Iterator for TerminalKeyStream {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        match self.events.recv_timeout(IDLE_AFTER) {
+            Ok(Input::Event(e)) => Some(e),
+            Ok(Input::End) => None,
+            // Panicking here too means unsaved changes get kept, same as for any other crash
+            Ok(Input::Crashed) => panic!("an input thread crashed"),
+            Err(RecvTimeoutError::Timeout) => Some(Event::Idle),
+            Err(RecvTimeoutError::Disconnected) => None
+        }
+    }
+}
+
+/// Tells the stream when an input thread stops, and whether it stopped by panicking
+struct Hangup(Sender<Input>);
+
+impl Drop for Hangup {
+    fn drop(&mut self) {
+        let _ = self.0.send(if thread::panicking() { Input::Crashed } else { Input::End });
+    }
 }
 
 impl Terminal {
-    pub fn keys(&mut self) -> iter::Map<termion::input::Events<io::Stdin>, fn(io::Result<event::Event>) -> Event> {
-        fn extract(e: io::Result<event::Event>) -> Event { Event::from(e.unwrap()) }
-        io::stdin().events().map(extract)
+    pub fn keys(&mut self) -> TerminalKeyStream {
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut signals) = Signals::new([SIGWINCH]) {
+            let hangup = Hangup(tx.clone());
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    if hangup.0.send(Input::Event(Event::Resize)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        thread::spawn(move || {
+            let hangup = Hangup(tx);
+            for e in io::stdin().events() {
+                match e {
+                    Ok(e) => if hangup.0.send(Input::Event(Event::from(e))).is_err() {
+                        break;
+                    },
+                    Err(_) => break
+                }
+            }
+        });
+        TerminalKeyStream {
+            events: rx
+        }
     }
 }
 
@@ -115,12 +229,21 @@ impl Write for Terminal {
 
 impl Default for Terminal {
     fn default() -> Terminal {
+        let screen = AlternateScreen::from(io::stdout().into_raw_mode().unwrap());
+        *SCREEN.lock().unwrap_or_else(|e| e.into_inner()) = Some(screen);
         Terminal {
-            out: AlternateScreen::from(io::stdout().into_raw_mode().unwrap()),
+            out: io::stdout(),
         }
     }
 }
 
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+        restore();
+    }
+}
+
 impl TermImpl for Terminal {
     fn get_size(&self) -> (i32, i32) {
         let (x, y) = termion::terminal_size().unwrap();
@@ -143,3 +266,20 @@ impl TermImpl for Terminal {
         write!(self.out, "{}", termion::clear::All).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifiers_on_special_keys() {
+        let shift = |k| Key::Shift(Box::new(k));
+        assert_eq!(modified_key(b"\x1B[1;2A"), Some(shift(Key::Up)));
+        assert_eq!(modified_key(b"\x1B[1;4D"), Some(Key::Alt(Box::new(shift(Key::Left)))));
+        assert_eq!(modified_key(b"\x1B[15;5~"), Some(Key::Ctrl(Box::new(Key::F(5)))));
+        assert_eq!(modified_key(b"\x1B[1;2F"), Some(shift(Key::End)));
+        assert_eq!(Key::from(event::Key::BackTab), shift(Key::Char('\t')));
+        assert_eq!(modified_key(b"\x1B[3~"), None);
+        assert_eq!(modified_key(b"\x1B[99;2~"), None);
+    }
+}
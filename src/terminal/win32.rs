@@ -3,6 +3,7 @@ extern crate kernel32;
 
 use std::io::{self, Write};
 use std::ptr;
+use std::sync::Mutex;
 
 use self::winapi::winnt;
 use self::winapi::winbase;
@@ -11,12 +12,25 @@ use self::winapi::winnls;
 
 use super::base::*;
 
+/// The console mode and code pages from before we started, kept out here so a panic can put them
+/// back from anywhere
+static ORIGINAL: Mutex<Option<(winapi::DWORD, winapi::UINT, winapi::UINT)>> = Mutex::new(None);
+
+/// Puts the console back the way it was, if that hasn't happened already
+pub fn restore() {
+    let original = ORIGINAL.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some((mode, in_cp, out_cp)) = original {
+        unsafe {
+            kernel32::SetConsoleMode(kernel32::GetStdHandle(winbase::STD_INPUT_HANDLE), mode);
+            kernel32::SetConsoleCP(in_cp);
+            kernel32::SetConsoleOutputCP(out_cp);
+        };
+    }
+}
+
 pub struct Terminal {
     stdin: winnt::HANDLE,
     stdout: winnt::HANDLE,
-    orig_mode: winapi::DWORD,
-    orig_in_cp: winapi::UINT,
-    orig_out_cp: winapi::UINT,
     char_attr_bg: winapi::WORD,
     char_attr_fg: winapi::WORD,
 }
@@ -114,6 +128,10 @@ impl Iterator for TerminalKeyStream {
 
     fn next(&mut self) -> Option<Event> {
         loop {
+            let timeout = IDLE_AFTER.as_secs() as winapi::DWORD * 1000 + IDLE_AFTER.subsec_millis();
+            if unsafe { kernel32::WaitForSingleObject(self.stdin, timeout) } == winapi::winerror::WAIT_TIMEOUT {
+                return Some(Event::Idle);
+            }
             let mut buf = wincon::INPUT_RECORD {
                 EventType: 0,
                 Event: [0, 0, 0, 0]
@@ -144,6 +162,7 @@ impl Iterator for TerminalKeyStream {
                         return Some(Event::Mouse(e));
                     }
                 },
+                wincon::WINDOW_BUFFER_SIZE_EVENT => return Some(Event::Resize),
                 _ => return Some(Event::Unsupported(buf.Event.to_vec()))
             }
         }
@@ -201,20 +220,21 @@ impl Write for Terminal {
 
 impl Default for Terminal {
     fn default() -> Terminal {
-        let mut result = Terminal {
+        let result = Terminal {
             // PSA: don't mix these up. That causes problems.
             stdin: unsafe { kernel32::GetStdHandle(winbase::STD_INPUT_HANDLE) },
             stdout: unsafe { kernel32::GetStdHandle(winbase::STD_OUTPUT_HANDLE) },
-            orig_mode: 0,
-            orig_in_cp: unsafe { kernel32::GetConsoleCP() },
-            orig_out_cp: unsafe { kernel32::GetConsoleOutputCP() },
             char_attr_bg: 0,
             char_attr_fg: (wincon::FOREGROUND_RED |
                 wincon::FOREGROUND_GREEN | wincon::FOREGROUND_BLUE) as winapi::WORD,
         };
+        let mut orig_mode: winapi::DWORD = 0;
         unsafe {
-            kernel32::GetConsoleMode(result.stdin, &mut result.orig_mode);
+            kernel32::GetConsoleMode(result.stdin, &mut orig_mode);
+            *ORIGINAL.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((orig_mode, kernel32::GetConsoleCP(), kernel32::GetConsoleOutputCP()));
             kernel32::SetConsoleMode(result.stdin, wincon::ENABLE_MOUSE_INPUT |
+                                     wincon::ENABLE_WINDOW_INPUT |
                                      wincon::ENABLE_EXTENDED_FLAGS);
             kernel32::SetConsoleCP(winnls::CP_UTF8);
             kernel32::SetConsoleOutputCP(winnls::CP_UTF8);
@@ -226,11 +246,7 @@ impl Default for Terminal {
 impl Drop for Terminal {
     fn drop(&mut self) {
         self.clear();
-        unsafe {
-            kernel32::SetConsoleMode(self.stdout, self.orig_mode);
-            kernel32::SetConsoleCP(self.orig_in_cp);
-            kernel32::SetConsoleOutputCP(self.orig_out_cp);
-        };
+        restore();
     }
 }
 